@@ -0,0 +1,182 @@
+use std::fs;
+use std::path::PathBuf;
+use clap::Parser;
+use crate::config::{self, Config};
+use crate::controller::{InitialSettings, ResumeMode};
+use crate::settings_changed::RepeatMode;
+
+/// Plays all the music files in a directory. Anything not given on the command line comes from the
+/// config file, $XDG_CONFIG_HOME/funoform_mp3_dir_player/config.toml.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Args {
+    /// Directory of music to play. Defaults to the first of the config file's music_dirs, then
+    /// $XDG_MUSIC_DIR, then the music directory in ~/.config/user-dirs.dirs, then ~/Music.
+    pub music_dir: Option<PathBuf>,
+
+    /// Read settings from this config file rather than the default one
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Include music files in subdirectories of the music directory
+    #[arg(long, overrides_with = "no_recursive")]
+    pub recursive: bool,
+
+    /// Only play music files directly in the music directory (the default)
+    #[arg(long, overrides_with = "recursive")]
+    pub no_recursive: bool,
+
+    /// Play songs in a random order (the default)
+    #[arg(long, overrides_with = "no_shuffle")]
+    pub shuffle: bool,
+
+    /// Play songs in the order they are found in the directory
+    #[arg(long, overrides_with = "shuffle")]
+    pub no_shuffle: bool,
+
+    /// What to do once a song finishes: off, all, or one, given as --repeat=MODE. Passing --repeat
+    /// on its own repeats all.
+    #[arg(long, value_name = "MODE", num_args = 0..=1, require_equals = true, default_missing_value = "all",
+          value_parser = parse_repeat_mode)]
+    pub repeat: Option<RepeatMode>,
+
+    /// Starting volume, from 0 (silent) to 100 (the song's natural volume). Defaults to the
+    /// volume from the last run.
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub volume: Option<u8>,
+
+    /// Don't start playing the music directory on startup
+    #[arg(long, overrides_with = "autoplay")]
+    pub no_autoplay: bool,
+
+    /// Start playing the music directory on startup (the default)
+    #[arg(long, overrides_with = "no_autoplay")]
+    pub autoplay: bool,
+
+    /// Whether to pick up where the last run left off: play, paused, or off. Defaults to play, or
+    /// off when a music directory is given.
+    #[arg(long, value_name = "MODE", value_parser = parse_resume_mode)]
+    pub resume: Option<ResumeMode>,
+}
+
+impl Args {
+    /// Gets the directory to start in, falling back to the config file, then the user's music
+    /// directory, if none was given on the command line
+    pub fn starting_dir(&self, config: &Config) -> PathBuf {
+        match (&self.music_dir, config.music_dirs.first()) {
+            (Some(dir), _) | (None, Some(dir)) => dir.clone(),
+            (None, None) => default_music_dir(),
+        }
+    }
+
+    /// Gets the controller settings requested on the command line, falling back to the config file
+    /// for anything that wasn't
+    pub fn initial_settings(&self, config: &Config) -> InitialSettings {
+        let defaults = InitialSettings::default();
+        // Asking for a particular directory means starting fresh in it
+        let default_resume = if self.music_dir.is_some() { ResumeMode::Off } else { config.resume.unwrap_or(defaults.resume) };
+        InitialSettings {
+            recursive: flag_pair(self.recursive, self.no_recursive).or(config.recursive).or(defaults.recursive),
+            random: flag_pair(self.shuffle, self.no_shuffle).or(config.shuffle).or(defaults.random),
+            repeat: self.repeat.or(config.repeat).or(defaults.repeat),
+            volume: self.volume.map(|percent| percent as f32 / 100.0).or(config.volume).or(defaults.volume),
+            resume: self.resume.unwrap_or(default_resume),
+        }
+    }
+
+    /// Returns true if we should start playing the music directory on startup
+    pub fn autoplay(&self, config: &Config) -> bool {
+        flag_pair(self.autoplay, self.no_autoplay).or(config.autoplay).unwrap_or(true)
+    }
+}
+
+/// Gets $XDG_MUSIC_DIR if it is set, otherwise the music directory from user-dirs.dirs, otherwise
+/// ~/Music
+fn default_music_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("XDG_MUSIC_DIR").filter(|dir| !dir.is_empty()) {
+        return PathBuf::from(dir);
+    }
+    let home = std::env::var("HOME").unwrap_or_default();
+    if let Some(dir) = user_dirs_music_dir(&home) {
+        return dir;
+    }
+    PathBuf::from(home).join("Music")
+}
+
+/// Reads the music directory from the user-dirs.dirs file kept by xdg-user-dirs, if there is one.
+/// The file is shell syntax, with lines like XDG_MUSIC_DIR="$HOME/Music".
+fn user_dirs_music_dir(home: &str) -> Option<PathBuf> {
+    let contents = fs::read_to_string(config::config_home()?.join("user-dirs.dirs")).ok()?;
+    parse_user_dirs_music_dir(&contents, home)
+}
+
+fn parse_user_dirs_music_dir(contents: &str, home: &str) -> Option<PathBuf> {
+    // As in the shell, the last setting wins
+    let value = contents.lines()
+        .rev()
+        .find_map(|line| line.trim().strip_prefix("XDG_MUSIC_DIR="))?;
+    let value = value.trim().strip_prefix('"')?.strip_suffix('"')?.replace("\\\"", "\"");
+    // Only $HOME and absolute paths are allowed, and a directory of just $HOME means it is disabled
+    let dir = match value.strip_prefix("$HOME") {
+        Some("" | "/") => return None,
+        Some(rest) if rest.starts_with('/') => format!("{}{}", home, rest),
+        None if value.starts_with('/') => value,
+        _ => return None,
+    };
+    Some(PathBuf::from(dir))
+}
+
+/// Gets the setting from a --flag/--no-flag pair, None if neither was given
+fn flag_pair(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_resume_mode(mode: &str) -> Result<ResumeMode, String> {
+    match mode.to_ascii_lowercase().as_str() {
+        "off" => Ok(ResumeMode::Off),
+        "paused" => Ok(ResumeMode::Paused),
+        "play" => Ok(ResumeMode::Playing),
+        _ => Err(format!("unknown resume mode '{}', expected play, paused, or off", mode)),
+    }
+}
+
+fn parse_repeat_mode(mode: &str) -> Result<RepeatMode, String> {
+    match mode.to_ascii_lowercase().as_str() {
+        "off" => Ok(RepeatMode::Off),
+        "all" => Ok(RepeatMode::All),
+        "one" => Ok(RepeatMode::One),
+        _ => Err(format!("unknown repeat mode '{}', expected off, all, or one", mode)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeat_on_its_own_leaves_music_dir_alone() {
+        let args = Args::try_parse_from(["player", "--repeat", "/music"]).unwrap();
+        assert_eq!(args.repeat, Some(RepeatMode::All));
+        assert_eq!(args.music_dir, Some(PathBuf::from("/music")));
+
+        let args = Args::try_parse_from(["player", "--repeat=one", "/music"]).unwrap();
+        assert_eq!(args.repeat, Some(RepeatMode::One));
+        assert_eq!(args.music_dir, Some(PathBuf::from("/music")));
+
+        assert!(Args::try_parse_from(["player", "--repeat=sometimes"]).is_err());
+    }
+
+    #[test]
+    fn reads_music_dir_from_user_dirs() {
+        let contents = "# Written by xdg-user-dirs-update\nXDG_DESKTOP_DIR=\"$HOME/Desktop\"\nXDG_MUSIC_DIR=\"$HOME/My Music\"\n";
+        assert_eq!(parse_user_dirs_music_dir(contents, "/home/sam"), Some(PathBuf::from("/home/sam/My Music")));
+        assert_eq!(parse_user_dirs_music_dir("XDG_MUSIC_DIR=\"/srv/music\"", "/home/sam"), Some(PathBuf::from("/srv/music")));
+        // Set to the home directory means there isn't one
+        assert_eq!(parse_user_dirs_music_dir("XDG_MUSIC_DIR=\"$HOME/\"", "/home/sam"), None);
+        assert_eq!(parse_user_dirs_music_dir("XDG_DESKTOP_DIR=\"$HOME/Desktop\"", "/home/sam"), None);
+    }
+}
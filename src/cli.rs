@@ -1,98 +1,456 @@
-use std::thread;
+use std::fs::{self, File};
 use std::io::{self, Write};
-use crossbeam_channel::{Receiver, Sender};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd, RawFd};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use tui::backend::CrosstermBackend;
+use tui::layout::{Constraint, Direction, Layout, Rect};
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{Block, Borders, Gauge, List, ListItem, ListState, Paragraph};
+use tui::{Frame, Terminal};
 use crate::controller::Controller;
+use crate::event_bus::Subscription;
+use crate::key_bindings::{Action, KeyBindings};
+use crate::metadata::metadata::TrackMetadata;
+use crate::player_event::{PlaybackState, PlayerEvent};
+use crate::saved_state::saved_state;
 use crate::settings_changed::SettingsChanged;
 
+/// How far the forward and back keys jump within the current song
+const SEEK_STEP_SECS: f64 = 10.0;
+
+/// How long we wait on a key press before redrawing with the latest settings
+const TICK: Duration = Duration::from_millis(250);
+
+/// The entry at the top of the directory browser that moves up to the parent directory
+const PARENT_DIR_ENTRY: &str = "..";
+
+/// The playlist the queue is saved to, in the browsing directory
+const SAVED_QUEUE_FILE_NAME: &str = "queue.m3u8";
+
+/// Where what the app prints goes while the UI is up, in the state directory
+const LOG_FILE_NAME: &str = "log.txt";
+
+/// The key bindings shown in the help pane. Actions that come in pairs share a line.
+const KEY_HELP: [(&[Action], &str); 21] = [
+    (&[Action::SelectUp, Action::SelectDown], "Select directory"),
+    (&[Action::OpenDir], "Open directory/play playlist"),
+    (&[Action::ParentDir], "Parent directory"),
+    (&[Action::NextMusicDir], "Next music directory"),
+    (&[Action::PlayDir], "Play browsing directory"),
+    (&[Action::Enqueue], "Add selected directory to queue"),
+    (&[Action::EnqueueNext], "Play selected directory next"),
+    (&[Action::ClearQueue], "Empty the queue"),
+    (&[Action::SaveQueue], "Save queue as playlist"),
+    (&[Action::PlayPause], "Play/pause"),
+    (&[Action::Stop], "Stop"),
+    (&[Action::Next], "Next"),
+    (&[Action::Previous], "Previous"),
+    (&[Action::SeekForward, Action::SeekBack], "Forward/back 10 seconds"),
+    (&[Action::VolumeUp, Action::VolumeDown], "Volume up/down"),
+    (&[Action::Mute], "Mute/unmute"),
+    (&[Action::Repeat], "Repeat mode (off/all/one)"),
+    (&[Action::Shuffle], "Shuffle on/off"),
+    (&[Action::Recursive], "Recursive on/off"),
+    (&[Action::Rescan], "Rescan library"),
+    (&[Action::Quit], "Quit"),
+];
+
 pub struct Cli {
     _thread: thread::JoinHandle<()>,
 }
 
+/// Puts the terminal into full screen raw mode and restores it when dropped, even if the UI thread
+/// panics
+struct TerminalGuard {
+    terminal: Terminal<CrosstermBackend<File>>,
+    // Dropped after the terminal is put back, so anything printed on the way out is seen
+    _log: LogRedirect,
+}
+
+impl TerminalGuard {
+    fn init() -> io::Result<TerminalGuard> {
+        let _log = LogRedirect::init()?;
+        let mut screen = _log.terminal()?;
+        enable_raw_mode()?;
+        execute!(screen, EnterAlternateScreen)?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(screen))?;
+        terminal.hide_cursor()?;
+        terminal.clear()?;
+        Ok(TerminalGuard { terminal, _log })
+    }
+}
+
+/// While the UI has the terminal, sends everything the rest of the app (and the libraries it uses)
+/// prints to the log file instead, so none of it lands on top of the UI. The log is
+/// $XDG_STATE_HOME/funoform_mp3_dir_player/log.txt, and is started over each run. Puts stdout and
+/// stderr back when dropped.
+struct LogRedirect {
+    // The original stdout and stderr
+    stdout: OwnedFd,
+    stderr: OwnedFd,
+}
+
+impl LogRedirect {
+    fn init() -> io::Result<LogRedirect> {
+        let log = match saved_state::state_dir() {
+            Some(dir) => fs::create_dir_all(&dir).and_then(|_| File::create(dir.join(LOG_FILE_NAME))),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "No state directory")),
+        };
+        // Losing the messages is better than letting them mess up the screen
+        let log = log.or_else(|_| File::create("/dev/null"))?;
+
+        io::stdout().flush()?;
+        let stdout = io::stdout().as_fd().try_clone_to_owned()?;
+        let stderr = io::stderr().as_fd().try_clone_to_owned()?;
+        redirect(log.as_raw_fd(), libc::STDOUT_FILENO)?;
+        redirect(log.as_raw_fd(), libc::STDERR_FILENO)?;
+        Ok(LogRedirect { stdout, stderr })
+    }
+
+    /// Gets the original stdout, for the UI to draw on
+    fn terminal(&self) -> io::Result<File> {
+        Ok(File::from(self.stdout.try_clone()?))
+    }
+}
+
+impl Drop for LogRedirect {
+    fn drop(&mut self) {
+        let _ = io::stdout().flush();
+        let _ = redirect(self.stdout.as_raw_fd(), libc::STDOUT_FILENO);
+        let _ = redirect(self.stderr.as_raw_fd(), libc::STDERR_FILENO);
+    }
+}
+
+/// Points one file descriptor at the same file as another
+fn redirect(from: RawFd, to: RawFd) -> io::Result<()> {
+    // SAFETY: dup2 only takes file descriptor numbers, and both are open for as long as we use them
+    if unsafe { libc::dup2(from, to) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(self.terminal.backend_mut(), LeaveAlternateScreen);
+        let _ = self.terminal.show_cursor();
+    }
+}
+
+/// Everything the UI displays
+struct CliState {
+    settings: SettingsChanged,
+    browsing_dir: String,
+    // The entries in the browsing directory: the parent directory, subdirectories, then playlists
+    dirs: Vec<String>,
+    // Index into dirs of the first playlist
+    first_playlist: usize,
+    dir_selection: ListState,
+    // File names of the queued songs and the position of the current one
+    queue: Vec<String>,
+    queue_index: Option<usize>,
+    // The settings' queue_version when we last fetched the queue
+    queue_version: Option<u64>,
+    // One line message about the result of the last thing the user did
+    message: String,
+    key_bindings: KeyBindings,
+    // The music directories from the config file, which NextMusicDir steps through
+    music_dirs: Vec<PathBuf>,
+    done: bool,
+}
+
+impl CliState {
+    /// Brings our copy of the controller's state up to date with something that happened
+    fn apply_event(&mut self, ctrl: &Controller, player_event: PlayerEvent) {
+        match player_event {
+            PlayerEvent::TrackStarted(song, tags) => {
+                self.settings.song_playing = song.to_string_lossy().to_string();
+                self.settings.now_playing = tags;
+            }
+            PlayerEvent::TrackEnded(song) => {
+                if self.settings.song_playing == song.to_string_lossy() {
+                    self.settings.song_playing = String::new();
+                    self.settings.now_playing = None;
+                    self.settings.song_time = (0, None);
+                }
+            }
+            PlayerEvent::PositionChanged(elapsed, total) => self.settings.song_time = (elapsed, total),
+            PlayerEvent::StateChanged(playback_state) => self.settings.state = playback_state,
+            PlayerEvent::ModeChanged(modes) => {
+                self.settings.random = modes.random;
+                self.settings.repeat = modes.repeat;
+                self.settings.recursive = modes.recursive;
+                self.settings.volume = modes.volume;
+                self.settings.muted = modes.muted;
+            }
+            PlayerEvent::QueueChanged(len, version) => {
+                self.settings.queue_len = len;
+                self.settings.queue_version = version;
+            }
+            PlayerEvent::DirChanged(browsing_dir, playing_dir) => {
+                self.settings.playing_dir = playing_dir;
+                // Another interface may have moved the browser somewhere else
+                if browsing_dir != self.browsing_dir {
+                    self.refresh_dirs(ctrl);
+                }
+                self.settings.browsing_dir = browsing_dir;
+            }
+            // Let the user know about songs that couldn't be played, even ones we skipped past
+            PlayerEvent::Error(e) => self.message = e,
+            PlayerEvent::Resync => {
+                self.settings = ctrl.snapshot();
+                self.queue_version = None;
+                if self.settings.browsing_dir != self.browsing_dir {
+                    self.refresh_dirs(ctrl);
+                }
+            }
+        }
+    }
+
+    fn init(ctrl: &Controller, key_bindings: KeyBindings, music_dirs: Vec<PathBuf>) -> CliState {
+        let mut state = CliState {
+            settings: ctrl.snapshot(),
+            browsing_dir: String::new(),
+            dirs: Vec::new(),
+            first_playlist: 0,
+            dir_selection: ListState::default(),
+            queue: Vec::new(),
+            queue_index: None,
+            queue_version: None,
+            message: String::new(),
+            key_bindings,
+            music_dirs,
+            done: false,
+        };
+        state.refresh_dirs(ctrl);
+        state
+    }
+
+    /// Reloads the directory browser from the controller's browsing directory
+    fn refresh_dirs(&mut self, ctrl: &Controller) {
+        self.browsing_dir = ctrl.get_browsing_dir();
+        self.dirs = vec![PARENT_DIR_ENTRY.to_string()];
+        match ctrl.get_available_dirs() {
+            Ok(mut sub_dirs) => {
+                sub_dirs.sort();
+                self.dirs.append(&mut sub_dirs);
+            }
+            Err(e) => self.message = format!("Unable to list {}: {}", self.browsing_dir, e),
+        }
+
+        // Playlists go after the directories. Opening one plays it.
+        self.first_playlist = self.dirs.len();
+        if let Ok(mut playlists) = ctrl.get_available_playlists() {
+            playlists.sort();
+            self.dirs.append(&mut playlists);
+        }
+        self.dir_selection.select(Some(0));
+    }
+
+    /// Fetches the queue from the controller if it has changed since we last fetched it
+    fn refresh_queue(&mut self, ctrl: &Controller) {
+        if self.queue_version == Some(self.settings.queue_version) {
+            return;
+        }
+        let (queue, queue_index) = ctrl.get_queue();
+        self.queue = queue.iter()
+            .map(|song| song.file_stem().map(|name| name.to_string_lossy().to_string()).unwrap_or_default())
+            .collect();
+        self.queue_index = queue_index;
+        self.queue_version = Some(self.settings.queue_version);
+    }
+
+    /// Gets the path of the selected directory or playlist. The parent directory entry means the
+    /// browsing directory itself.
+    fn selected_dir(&self) -> Option<PathBuf> {
+        let selected = self.dir_selection.selected().and_then(|i| self.dirs.get(i))?;
+        if selected == PARENT_DIR_ENTRY {
+            Some(PathBuf::from(&self.browsing_dir))
+        } else {
+            Some(PathBuf::from(&self.browsing_dir).join(selected))
+        }
+    }
+
+    fn enqueue_selected_dir(&mut self, ctrl: &mut Controller, play_next: bool) {
+        let Some(dir) = self.selected_dir() else {
+            return;
+        };
+        let dir = dir.to_string_lossy().to_string();
+        let res = if play_next {
+            ctrl.enqueue_next(&dir)
+        } else {
+            ctrl.enqueue(&dir)
+        };
+        match res {
+            Ok(count) => self.message = format!("Queued {} songs from {}", count, dir),
+            Err(e) => self.message = format!("Unable to queue: {}", e),
+        }
+    }
+
+    fn move_selection(&mut self, offset: i64) {
+        let cur = self.dir_selection.selected().unwrap_or(0) as i64;
+        let last = self.dirs.len() as i64 - 1;
+        self.dir_selection.select(Some((cur + offset).clamp(0, last.max(0)) as usize));
+    }
+
+    fn browse_to(&mut self, ctrl: &mut Controller, dir: PathBuf) {
+        let dir = dir.to_string_lossy().to_string();
+        match ctrl.set_browsing_dir(&dir, false) {
+            Ok(_) => self.refresh_dirs(ctrl),
+            Err(e) => self.message = format!("Unable to browse: {}", e),
+        }
+    }
+
+    fn open_selected_dir(&mut self, ctrl: &mut Controller) {
+        let Some(selected_index) = self.dir_selection.selected() else {
+            return;
+        };
+        let Some(selected) = self.dirs.get(selected_index) else {
+            return;
+        };
+        if selected_index >= self.first_playlist {
+            let playlist = PathBuf::from(&self.browsing_dir).join(selected).to_string_lossy().to_string();
+            match ctrl.play_playlist(&playlist) {
+                Ok(_) => self.message = format!("Playing {}", playlist),
+                Err(e) => self.message = format!("Unable to play playlist: {}", e),
+            }
+        } else if selected == PARENT_DIR_ENTRY {
+            self.open_parent_dir(ctrl);
+        } else {
+            let dir = PathBuf::from(&self.browsing_dir).join(selected);
+            self.browse_to(ctrl, dir);
+        }
+    }
+
+    fn open_parent_dir(&mut self, ctrl: &mut Controller) {
+        if let Some(parent) = PathBuf::from(&self.browsing_dir).parent() {
+            self.browse_to(ctrl, parent.to_path_buf());
+        }
+    }
+
+    /// Browses the music directory after the one we are in, going back to the first after the last
+    fn open_next_music_dir(&mut self, ctrl: &mut Controller) {
+        let browsing_dir = PathBuf::from(&self.browsing_dir);
+        let next_index = match self.music_dirs.iter().position(|dir| browsing_dir.starts_with(dir)) {
+            Some(index) => (index + 1) % self.music_dirs.len(),
+            None => 0,
+        };
+        match self.music_dirs.get(next_index) {
+            Some(dir) => self.browse_to(ctrl, dir.clone()),
+            None => self.message = "No music_dirs listed in the config file".to_string(),
+        }
+    }
+
+    /// Acts on a single key press
+    fn handle_key(&mut self, ctrl: &mut Controller, key: KeyEvent) {
+        self.message.clear();
+        // Raw mode keeps Ctrl+C from interrupting us, so quit as the user expects
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            self.done = true;
+            return;
+        }
+        let Some(action) = self.key_bindings.action(key) else {
+            return;
+        };
+        match action {
+            Action::SelectUp => self.move_selection(-1),
+            Action::SelectDown => self.move_selection(1),
+            Action::PageUp => self.move_selection(-10),
+            Action::PageDown => self.move_selection(10),
+            Action::OpenDir => self.open_selected_dir(ctrl),
+            Action::ParentDir => self.open_parent_dir(ctrl),
+            Action::NextMusicDir => self.open_next_music_dir(ctrl),
+            Action::PlayDir => {
+                match ctrl.play_browsing_dir() {
+                    Ok(_) => self.message = format!("Playing {}", self.browsing_dir),
+                    Err(e) => self.message = format!("Unable to play directory: {}", e),
+                }
+            }
+            Action::Enqueue => self.enqueue_selected_dir(ctrl, false),
+            Action::EnqueueNext => self.enqueue_selected_dir(ctrl, true),
+            Action::ClearQueue => ctrl.clear_queue(),
+            Action::SaveQueue => {
+                let playlist = PathBuf::from(&self.browsing_dir).join(SAVED_QUEUE_FILE_NAME).to_string_lossy().to_string();
+                match ctrl.save_queue(&playlist) {
+                    Ok(_) => {
+                        self.message = format!("Saved queue to {}", playlist);
+                        self.refresh_dirs(ctrl);
+                    }
+                    Err(e) => self.message = format!("Unable to save queue: {}", e),
+                }
+            }
+            Action::PlayPause => {
+                if let Err(e) = ctrl.play_pause() {
+                    self.message = format!("Unable to play: {}", e);
+                }
+            }
+            Action::Stop => ctrl.stop(),
+            Action::Next => {
+                if let Err(e) = ctrl.next() {
+                    self.message = format!("Unable to play the next song: {}", e);
+                }
+            }
+            Action::Previous => {
+                if let Err(e) = ctrl.prior_track() {
+                    self.message = format!("Unable to play the previous song: {}", e);
+                }
+            }
+            Action::SeekForward | Action::SeekBack => {
+                let offset = if action == Action::SeekForward { SEEK_STEP_SECS } else { -SEEK_STEP_SECS };
+                if let Err(e) = ctrl.seek_by(offset) {
+                    self.message = format!("Unable to seek: {}", e);
+                }
+            }
+            Action::VolumeUp => ctrl.volume_up(),
+            Action::VolumeDown => ctrl.volume_down(),
+            Action::Mute => ctrl.toggle_mute(),
+            Action::Repeat => {
+                ctrl.cycle_repeat_mode();
+            }
+            Action::Shuffle => ctrl.set_random(!self.settings.random),
+            Action::Recursive => ctrl.set_recursive(!self.settings.recursive),
+            Action::Rescan => {
+                match ctrl.rescan() {
+                    Ok(count) => self.message = format!("Rescanned {} music files", count),
+                    Err(e) => self.message = format!("Unable to rescan: {}", e),
+                }
+            }
+            Action::Quit => self.done = true,
+        }
+    }
+}
+
 impl Cli {
-    pub fn init(mut ctrl: Controller) -> Cli {
-        // Get notified by the controller when settings have changed
-        println!("CLI: about to register settings listener");
-        let ctrl_settings_listener: Receiver<SettingsChanged> = ctrl.register_settings_listener();
-
-        // Start playing the browsing directory
-        println!("CLI: about to play dir");
-        ctrl.play_browsing_dir().unwrap_or_else(|e| {
-            eprintln!("Failed to play music files: {}", e);
-        });
+    /// Starts the terminal UI on its own thread. It runs until the user quits or quit is set.
+    pub fn init(mut ctrl: Controller, autoplay: bool, key_bindings: KeyBindings, music_dirs: Vec<PathBuf>,
+                quit: Arc<AtomicBool>) -> Cli {
+        // Get notified by the controller when anything changes
+        let ctrl_events: Subscription<PlayerEvent> = ctrl.subscribe();
+
+        // Start playing the browsing directory, unless we picked up a queue from the last run
+        if autoplay && ctrl.get_queue().0.is_empty() {
+            ctrl.play_browsing_dir().unwrap_or_else(|e| {
+                eprintln!("Failed to play music files: {}", e);
+            });
+        }
 
         // Spawn a thread for CLI interaction
-        println!("CLI: about to spawn thread");
         let thread: thread::JoinHandle<()> = thread::spawn(move || {
-            println!("CLI: before loop");
-            loop {
-                // Check for settings changes
-                // match ctrl_settings_listener.try_recv() {
-                //     Ok(s) => {
-                //         println!("CLI: New settings {:?}", s);
-                //     }
-                //     Err(_) => {
-                //         // No new settings, just sleep for a bit
-                //         std::thread::sleep(std::time::Duration::from_secs(1));
-                //     }
-                // };
-
-                // Display CLI menu
-                println!("\nCLI Menu:");
-                println!("1. Specify directory");
-                println!("2. Play");
-                println!("3. Pause");
-                println!("4. Stop");
-                println!("n - Next");
-                println!("s - Show status");
-                println!("x - Exit");
-                print!("Enter your choice: ");
-                io::stdout().flush().unwrap();
-
-                // Read user input
-                let mut input = String::new();
-                io::stdin().read_line(&mut input).unwrap();
-                let choice = input.trim();
-
-                match choice {
-                    "1" => {
-                        println!("Enter the directory path:");
-                        let mut dir_input = String::new();
-                        io::stdin().read_line(&mut dir_input).unwrap();
-                        let dir_path = dir_input.trim();
-                        // TODO:
-                    }
-                    "2" => {
-                        ctrl.play();
-                    }
-                    "3" => {
-                        ctrl.pause();
-                    }
-                    "4" => {
-                        ctrl.stop();
-                    }
-                    "n" => {
-                        ctrl.next();
-                    }
-                    "s" => {
-                        match ctrl_settings_listener.try_recv() {
-                            Ok(status) => {
-                                println!("CLI: Playback status {:?}", status);
-                            },
-                            Err(_) => println!("No status updates available."),
-                        }
-                    }
-                    "x" => {
-                        println!("Exiting...");
-                        break;
-                    }
-                    _ => {
-                        println!("Invalid choice. Please try again.");
-                    }
-                }
+            if let Err(e) = Cli::run(&mut ctrl, &ctrl_events, key_bindings, music_dirs, &quit) {
+                eprintln!("Terminal UI failed: {}", e);
             }
+            ctrl.save_session();
         });
 
-        println!("CLI: after thread");
         Cli {
             _thread: thread,
         }
@@ -101,4 +459,177 @@ impl Cli {
     pub fn is_done(&self) -> bool {
         self._thread.is_finished()
     }
-}
\ No newline at end of file
+
+    /// Draws the UI and handles key presses until the user quits
+    fn run(ctrl: &mut Controller, events: &Subscription<PlayerEvent>, key_bindings: KeyBindings,
+           music_dirs: Vec<PathBuf>, quit: &AtomicBool) -> io::Result<()> {
+        let mut guard = TerminalGuard::init()?;
+        let mut state = CliState::init(ctrl, key_bindings, music_dirs);
+
+        while !state.done && !quit.load(Ordering::Relaxed) {
+            let prev_song = state.settings.song_playing.clone();
+            for player_event in events.receiver().try_iter() {
+                state.apply_event(ctrl, player_event);
+            }
+            if prev_song != state.settings.song_playing {
+                // The current song's position in the queue has moved
+                state.queue_version = None;
+            }
+            state.refresh_queue(ctrl);
+            ctrl.save_session_if_due();
+
+            guard.terminal.draw(|f| draw(f, &mut state))?;
+
+            if event::poll(TICK)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press {
+                        state.handle_key(ctrl, key);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats a number of seconds as minutes and seconds
+fn format_time(secs: u32) -> String {
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+fn on_off(is_on: bool) -> &'static str {
+    if is_on {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn draw(f: &mut Frame<CrosstermBackend<File>>, state: &mut CliState) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(7),
+            Constraint::Length(3),
+            Constraint::Min(5),
+            Constraint::Length(1),
+        ])
+        .split(f.size());
+
+    draw_now_playing(f, rows[0], &state.settings);
+    draw_progress(f, rows[1], &state.settings);
+
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(35), Constraint::Percentage(35), Constraint::Percentage(30)])
+        .split(rows[2]);
+    draw_dir_browser(f, columns[0], state);
+    draw_queue(f, columns[1], state);
+    draw_key_help(f, columns[2], &state.key_bindings);
+
+    f.render_widget(Paragraph::new(state.message.as_str()), rows[3]);
+}
+
+fn draw_now_playing(f: &mut Frame<CrosstermBackend<File>>, area: Rect, settings: &SettingsChanged) {
+    let label_style = Style::default().add_modifier(Modifier::BOLD);
+    let mut song = match &settings.now_playing {
+        _ if settings.song_playing.is_empty() => "Nothing playing".to_string(),
+        Some(track_metadata) => track_metadata.display_name(),
+        None => settings.song_playing.clone(),
+    };
+    if settings.state == PlaybackState::Paused {
+        song.push_str(" (paused)");
+    }
+    let album = match &settings.now_playing {
+        Some(TrackMetadata { album: Some(album), year: Some(year), .. }) => format!("{} ({})", album, year),
+        Some(TrackMetadata { album: Some(album), .. }) => album.clone(),
+        _ => String::new(),
+    };
+    let volume = if settings.muted {
+        "muted".to_string()
+    } else {
+        format!("{:.0}%", settings.volume * 100.0)
+    };
+
+    let lines = vec![
+        Spans::from(vec![Span::styled("Song: ", label_style), Span::raw(song)]),
+        Spans::from(vec![Span::styled("Album: ", label_style), Span::raw(album)]),
+        Spans::from(vec![
+            Span::styled("Playing dir: ", label_style),
+            Span::raw(format!("{} ({} songs)", settings.playing_dir, settings.queue_len)),
+        ]),
+        Spans::from(vec![
+            Span::styled("Shuffle: ", label_style),
+            Span::raw(on_off(settings.random)),
+            Span::styled("  Repeat: ", label_style),
+            Span::raw(format!("{:?}", settings.repeat)),
+            Span::styled("  Recursive: ", label_style),
+            Span::raw(on_off(settings.recursive)),
+            Span::styled("  Volume: ", label_style),
+            Span::raw(volume),
+        ]),
+    ];
+    let now_playing = Paragraph::new(lines)
+        .block(Block::default().borders(Borders::ALL).title("Now Playing"));
+    f.render_widget(now_playing, area);
+}
+
+fn draw_progress(f: &mut Frame<CrosstermBackend<File>>, area: Rect, settings: &SettingsChanged) {
+    // Without the length of the song, all we can show is how far in we are
+    let (ratio, label) = match settings.song_time {
+        (elapsed, Some(total)) if total > 0 => {
+            ((elapsed as f64 / total as f64).clamp(0.0, 1.0), format!("{} / {}", format_time(elapsed), format_time(total)))
+        }
+        (elapsed, _) => (0.0, format_time(elapsed)),
+    };
+    let gauge = Gauge::default()
+        .block(Block::default().borders(Borders::ALL))
+        .gauge_style(Style::default().fg(Color::Cyan))
+        .ratio(ratio)
+        .label(label);
+    f.render_widget(gauge, area);
+}
+
+fn draw_dir_browser(f: &mut Frame<CrosstermBackend<File>>, area: Rect, state: &mut CliState) {
+    let items: Vec<ListItem> = state.dirs.iter().map(|dir| ListItem::new(dir.as_str())).collect();
+    let browser = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title(state.browsing_dir.as_str()))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+        .highlight_symbol("> ");
+    f.render_stateful_widget(browser, area, &mut state.dir_selection);
+}
+
+fn draw_queue(f: &mut Frame<CrosstermBackend<File>>, area: Rect, state: &CliState) {
+    // Queues can be huge, so only build items for the part around the current song that fits
+    let visible = area.height.saturating_sub(2) as usize;
+    let cur = state.queue_index.unwrap_or(0);
+    let start = cur.saturating_sub(visible / 3).min(state.queue.len().saturating_sub(visible));
+    let items: Vec<ListItem> = state.queue.iter()
+        .enumerate()
+        .skip(start)
+        .take(visible)
+        .map(|(index, song)| {
+            if Some(index) == state.queue_index {
+                ListItem::new(format!("> {}", song)).style(Style::default().add_modifier(Modifier::BOLD))
+            } else {
+                ListItem::new(format!("  {}", song))
+            }
+        })
+        .collect();
+    let title = format!("Queue ({})", state.queue.len());
+    let queue = List::new(items).block(Block::default().borders(Borders::ALL).title(title));
+    f.render_widget(queue, area);
+}
+
+fn draw_key_help(f: &mut Frame<CrosstermBackend<File>>, area: Rect, key_bindings: &KeyBindings) {
+    let key_style = Style::default().add_modifier(Modifier::BOLD);
+    let lines: Vec<Spans> = KEY_HELP
+        .iter()
+        .map(|(actions, description)| {
+            let keys: Vec<String> = actions.iter().map(|action| key_bindings.key_name(*action)).collect();
+            Spans::from(vec![Span::styled(format!("{:<10}", keys.join("/")), key_style), Span::raw(*description)])
+        })
+        .collect();
+    let help = Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title("Keys"));
+    f.render_widget(help, area);
+}
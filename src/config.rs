@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use glob::Pattern;
+use serde::Deserialize;
+use crate::controller::ResumeMode;
+use crate::file_utils::file_utils;
+use crate::key_bindings::KeyBindings;
+use crate::saved_state::saved_state::APP_DIR_NAME;
+use crate::settings_changed::RepeatMode;
+
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// The settings from the config file. Anything left out of the file falls back to the command
+/// line, then to the built in defaults.
+#[derive(Debug)]
+pub struct Config {
+    // Where the config was read from, None if there is no config file
+    pub path: Option<PathBuf>,
+    // The music directories the browser can jump between. The first is where we start.
+    pub music_dirs: Vec<PathBuf>,
+    pub recursive: Option<bool>,
+    pub shuffle: Option<bool>,
+    pub repeat: Option<RepeatMode>,
+    // Volume from 0.0 to 1.0
+    pub volume: Option<f32>,
+    pub autoplay: Option<bool>,
+    pub resume: Option<ResumeMode>,
+    // Lower case, without the leading dot
+    pub extensions: Vec<String>,
+    pub exclude: Vec<Pattern>,
+    pub key_bindings: KeyBindings,
+    // Whether to offer the MPRIS interface on the D-Bus session bus
+    pub mpris: bool,
+    // Where to listen for MPD clients, None to not run the MPD server
+    pub mpd: Option<SocketAddr>,
+    // Where to answer the HTTP API, None to not run it
+    pub http: Option<SocketAddr>,
+    // The token HTTP API requests must carry, None to not ask for one
+    pub http_token: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::check(ConfigFile::default()).expect("The default config is valid")
+    }
+}
+
+/// The config file as written, before it has been checked
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    music_dirs: Vec<String>,
+    recursive: Option<bool>,
+    shuffle: Option<bool>,
+    repeat: Option<RepeatMode>,
+    volume: Option<u8>,
+    autoplay: Option<bool>,
+    resume: Option<ResumeMode>,
+    extensions: Option<Vec<String>>,
+    exclude: Vec<String>,
+    keys: HashMap<String, Keys>,
+    mpris: Option<bool>,
+    mpd: Option<String>,
+    http: Option<String>,
+    http_token: Option<String>,
+}
+
+/// The keys bound to an action. A single key can be given on its own rather than in a list.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Keys {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// A config file that couldn't be read, or has a mistake in it
+#[derive(Debug)]
+pub struct ConfigError {
+    path: PathBuf,
+    reason: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Error in config file {}: {}", self.path.display(), self.reason)
+    }
+}
+
+/// Gets the directory user config files go in. Follows the XDG base directory spec, so this is
+/// $XDG_CONFIG_HOME, or ~/.config if $XDG_CONFIG_HOME isn't set.
+pub fn config_home() -> Option<PathBuf> {
+    match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => Some(PathBuf::from(std::env::var_os("HOME")?).join(".config")),
+    }
+}
+
+/// Gets the default config file, which is in our directory under config_home
+pub fn default_config_file() -> Option<PathBuf> {
+    Some(config_home()?.join(APP_DIR_NAME).join(CONFIG_FILE_NAME))
+}
+
+impl Config {
+    /// Reads and checks the specified config file. A missing file is only an error if it was asked
+    /// for by name, otherwise every setting is left at its default.
+    /// # Parameters
+    /// path = The config file given on the command line, or None to use the default config file
+    pub fn load(path: Option<&Path>) -> Result<Config, ConfigError> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_config_file() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+        let error = |reason: String| ConfigError { path: path.clone(), reason };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => return Ok(Config::default()),
+            Err(e) => return Err(error(e.to_string())),
+        };
+        let config_file: ConfigFile = toml::from_str(&contents).map_err(|e| error(e.to_string()))?;
+        let mut config = Config::check(config_file).map_err(error)?;
+        config.path = Some(path);
+        Ok(config)
+    }
+
+    /// Checks the settings read from the config file make sense, turning them into a Config
+    fn check(config_file: ConfigFile) -> Result<Config, String> {
+        let mut music_dirs = Vec::new();
+        for dir in &config_file.music_dirs {
+            let path = expand_home(dir);
+            if !path.is_dir() {
+                return Err(format!("music_dirs: {} is not a directory", path.display()));
+            }
+            music_dirs.push(path);
+        }
+
+        if let Some(volume) = config_file.volume.filter(|&volume| volume > 100) {
+            return Err(format!("volume: {} is out of range, expected 0 to 100", volume));
+        }
+
+        let extensions = match config_file.extensions {
+            Some(extensions) => {
+                let extensions: Vec<String> = extensions.iter()
+                    .map(|ext| ext.trim().trim_start_matches('.').to_ascii_lowercase())
+                    .collect();
+                if extensions.is_empty() || extensions.iter().any(String::is_empty) {
+                    return Err("extensions: expected a list of file extensions like [\"mp3\", \"flac\"]".to_string());
+                }
+                extensions
+            }
+            None => file_utils::DEFAULT_EXTENSIONS.iter().map(|ext| ext.to_string()).collect(),
+        };
+
+        let mut exclude = Vec::new();
+        for pattern in &config_file.exclude {
+            match Pattern::new(pattern) {
+                Ok(pattern) => exclude.push(pattern),
+                Err(e) => return Err(format!("exclude: '{}' is not a valid pattern: {}", pattern, e)),
+            }
+        }
+
+        let mpd = config_file.mpd.as_deref().map(|address| parse_address("mpd", address, 6600)).transpose()?;
+        let http = config_file.http.as_deref().map(|address| parse_address("http", address, 8080)).transpose()?;
+        if config_file.http_token.as_deref().is_some_and(|token| token.is_empty() || token.contains(char::is_whitespace)) {
+            return Err("http_token: expected a token without spaces".to_string());
+        }
+
+        let key_overrides: HashMap<String, Vec<String>> = config_file.keys.into_iter()
+            .map(|(action, keys)| match keys {
+                Keys::One(key) => (action, vec![key]),
+                Keys::Many(keys) => (action, keys),
+            })
+            .collect();
+        let key_bindings = KeyBindings::init(&key_overrides).map_err(|e| format!("keys: {}", e))?;
+
+        Ok(Config {
+            path: None,
+            music_dirs,
+            recursive: config_file.recursive,
+            shuffle: config_file.shuffle,
+            repeat: config_file.repeat,
+            volume: config_file.volume.map(|percent| percent as f32 / 100.0),
+            autoplay: config_file.autoplay,
+            resume: config_file.resume,
+            extensions,
+            exclude,
+            key_bindings,
+            mpris: config_file.mpris.unwrap_or(true),
+            mpd,
+            http,
+            http_token: config_file.http_token,
+        })
+    }
+}
+
+/// Parses an address to listen on. A port on its own listens on localhost only.
+/// # Parameters
+/// example_port = The port shown in the error message when the address isn't valid
+fn parse_address(key: &str, address: &str, example_port: u16) -> Result<SocketAddr, String> {
+    match address.parse::<u16>() {
+        Ok(port) => Ok(SocketAddr::from(([127, 0, 0, 1], port))),
+        Err(_) => address.parse::<SocketAddr>().map_err(|_| {
+            format!("{}: '{}' is not a port or an address like \"127.0.0.1:{}\"", key, address, example_port)
+        }),
+    }
+}
+
+/// Expands a leading ~ in a path to the home directory
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ if path == "~" => std::env::var_os("HOME").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(path)),
+        _ => PathBuf::from(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_bindings::Action;
+
+    /// Checks a config file given as TOML, the way load does
+    fn check_toml(contents: &str) -> Result<Config, String> {
+        let config_file: ConfigFile = toml::from_str(contents).map_err(|e| e.to_string())?;
+        Config::check(config_file)
+    }
+
+    #[test]
+    fn empty_config_uses_defaults() {
+        let config = check_toml("").unwrap();
+        assert!(config.music_dirs.is_empty());
+        assert_eq!(config.extensions, file_utils::DEFAULT_EXTENSIONS);
+        assert!(config.mpris);
+        assert!(config.mpd.is_none() && config.http.is_none());
+    }
+
+    #[test]
+    fn settings_are_checked() {
+        let config = check_toml("volume = 40\nextensions = [\".MP3\", \" opus\"]\nexclude = [\"*.part\"]\n").unwrap();
+        assert_eq!(config.volume, Some(0.4));
+        assert_eq!(config.extensions, ["mp3", "opus"]);
+        assert!(check_toml("volume = 101").unwrap_err().starts_with("volume:"));
+        assert!(check_toml("extensions = []").unwrap_err().starts_with("extensions:"));
+        assert!(check_toml("extensions = [\".\"]").unwrap_err().starts_with("extensions:"));
+        assert!(check_toml("exclude = [\"[a\"]").unwrap_err().starts_with("exclude:"));
+        assert!(check_toml("music_dirs = [\"/no/such/dir\"]").unwrap_err().starts_with("music_dirs:"));
+        assert!(check_toml("http_token = \"a b\"").unwrap_err().starts_with("http_token:"));
+        assert!(check_toml("colour = \"blue\"").unwrap_err().contains("unknown field"));
+    }
+
+    #[test]
+    fn addresses_can_be_just_a_port() {
+        let config = check_toml("mpd = \"6601\"\nhttp = \"0.0.0.0:8081\"\n").unwrap();
+        assert_eq!(config.mpd, Some(SocketAddr::from(([127, 0, 0, 1], 6601))));
+        assert_eq!(config.http, Some(SocketAddr::from(([0, 0, 0, 0], 8081))));
+        let error = check_toml("mpd = \"localhost\"").unwrap_err();
+        assert_eq!(error, "mpd: 'localhost' is not a port or an address like \"127.0.0.1:6600\"");
+    }
+
+    #[test]
+    fn keys_can_be_one_or_many() {
+        let config = check_toml("[keys]\nnext = \"ctrl+n\"\nprevious = [\"ctrl+p\", \"P\"]\n").unwrap();
+        assert_eq!(config.key_bindings.key_name(Action::Next), "Ctrl+n");
+        assert_eq!(config.key_bindings.key_name(Action::Previous), "Ctrl+p");
+        let error = check_toml("[keys]\nstop = \"ctrl+c\"\n").unwrap_err();
+        assert!(error.starts_with("keys: stop: "), "{}", error);
+        assert!(check_toml("[keys]\nstop = \"q\"\n").unwrap_err().contains("bound to both"));
+    }
+
+    #[test]
+    fn missing_file_is_only_an_error_when_named() {
+        let path = std::env::temp_dir().join(format!("funoform_config_test_{}.toml", std::process::id()));
+        let error = Config::load(Some(&path)).unwrap_err();
+        assert!(error.to_string().starts_with(&format!("Error in config file {}", path.display())));
+
+        fs::write(&path, "volume = 120\n").unwrap();
+        let error = Config::load(Some(&path)).unwrap_err();
+        assert!(error.to_string().ends_with("volume: 120 is out of range, expected 0 to 100"));
+
+        fs::write(&path, "shuffle = true\n").unwrap();
+        let config = Config::load(Some(&path)).unwrap();
+        assert_eq!(config.path.as_deref(), Some(path.as_path()));
+        assert_eq!(config.shuffle, Some(true));
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -1,11 +1,80 @@
 use std::io;
 use rand::Rng;
-use crossbeam_channel::{unbounded, Sender, Receiver};
+use rand::seq::SliceRandom;
 use std::sync::{Arc, Mutex, mpsc};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Deserialize;
 
-use crate::music_player::{MusicPlayer, PlaybackControls, PlaybackStatus};
+use crate::music_player::{MusicPlayer, PlaybackControls, PlaybackStatus, PlayerError};
 use crate::file_utils::file_utils;
-use crate::settings_changed::SettingsChanged;
+use crate::metadata::metadata::TrackMetadata;
+use crate::library::library::LibraryIndex;
+use crate::settings_changed::{RepeatMode, SettingsChanged};
+use crate::saved_state::saved_state::{self, Session};
+use crate::event_bus::{EventBus, Subscription};
+use crate::player_event::{PlaybackState, PlayerEvent};
+
+/// How much volume_up and volume_down change the volume by
+const VOLUME_STEP: f32 = 0.05;
+
+/// If the current song has played longer than this, prior_track restarts it rather than going back
+/// to the song before it
+const PRIOR_TRACK_RESTART_SECS: u32 = 3;
+
+/// The most songs we remember having played, for going back through with prior_track
+const MAX_PLAY_HISTORY: usize = 1000;
+
+/// How often the session is saved while songs are playing, so a crash loses at most this much of
+/// the position in the current song
+const SESSION_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long after anything else in the session changes it is saved. Changes that come in quick
+/// succession, like holding down the volume key or removing a whole directory from the queue, are
+/// saved together.
+const SESSION_CHANGE_SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// How many events a listener can fall behind by before it is told to resync
+const EVENT_QUEUE_LEN: usize = 256;
+
+/// Whether to pick up where the last run left off
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResumeMode {
+    /// Start fresh, ignoring the last run's queue and modes
+    Off,
+    /// Restore the last run's queue and modes, with the song that was playing paused where it was
+    Paused,
+    /// Restore the last run's queue and modes, and carry on playing
+    #[serde(rename = "play")]
+    Playing,
+}
+
+/// The settings the controller starts up with
+#[derive(Debug, Clone)]
+pub struct InitialSettings {
+    // The modes are None when not specified, meaning use the last run's modes when resuming, or
+    // the defaults when not
+    pub recursive: Option<bool>,
+    pub random: Option<bool>,
+    pub repeat: Option<RepeatMode>,
+    // Volume from 0.0 to 1.0. None means use the volume saved by the last run.
+    pub volume: Option<f32>,
+    pub resume: ResumeMode,
+}
+
+impl Default for InitialSettings {
+    fn default() -> Self {
+        InitialSettings {
+            recursive: None,
+            random: None,
+            repeat: None,
+            volume: None,
+            resume: ResumeMode::Playing,
+        }
+    }
+}
 
 /// Tracks the status of the currently playing song and enqueues the next one once the current finishes
 struct MonSongThread {
@@ -19,21 +88,12 @@ impl MonSongThread {
             move || {
                 loop {
                     match song_over_l.recv() {
-                        Ok(PlaybackStatus::PlaybackComplete(_)) => {
-                            println!("Song finished playing.");
-                            song_ctrl.lock().unwrap().play_next_song();
-                        }
-                        Ok(PlaybackStatus::PlaybackPercentage(elapsed, total)) => {
-                            // println!("Song playback at {}/{}", elapsed, total);
-                            song_ctrl.lock().unwrap()._cur_settings.song_time = (elapsed, total);
-                        }
+                        Ok(status) => song_ctrl.lock().unwrap().handle_playback_status(status),
                         Err(e) => {
                             eprintln!("Error receiving song over notification: {}", e);
                             break;
                         }
                     }
-                    // Notify listeners of the current settings
-                    song_ctrl.lock().unwrap().send_settings();
                 }
             }
         });
@@ -45,175 +105,1154 @@ impl MonSongThread {
     }
 }
 
+/// Keeps the queue in sync with the playing directory as music files are added to or removed from it
+struct MonDirThread {
+    _song_ctrl: Arc<Mutex<SongControlThread>>,
+    _thread: Option<std::thread::JoinHandle<()>>,
+}
+impl MonDirThread {
+    pub fn init(song_ctrl: Arc<Mutex<SongControlThread>>, dir_event_l: mpsc::Receiver<notify::Result<notify::Event>>) -> MonDirThread {
+        let _thread = std::thread::spawn({
+            let song_ctrl = Arc::clone(&song_ctrl);
+            move || {
+                loop {
+                    match dir_event_l.recv() {
+                        Ok(Ok(event)) => {
+                            // Rather than decode every kind of event (and every platform's take on
+                            // renames), just check whether each path the event mentions still exists
+                            let mut song_ctrl = song_ctrl.lock().unwrap();
+                            let mut changed = false;
+                            for path in event.paths {
+                                changed |= if path.exists() {
+                                    song_ctrl.path_added(&path)
+                                } else {
+                                    song_ctrl.path_removed(&path)
+                                };
+                            }
+                            if changed {
+                                println!("Queue now has {} songs", song_ctrl._queued_music_files.len());
+                            }
+                        }
+                        Ok(Err(e)) => {
+                            eprintln!("Error watching the playing directory: {}", e);
+                        }
+                        Err(e) => {
+                            eprintln!("Error receiving directory change notification: {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        MonDirThread {
+            _song_ctrl: song_ctrl,
+            _thread: Some(_thread),
+        }
+    }
+}
+
+/// Keeps track of a song in the queue for remote clients
+#[derive(Debug, Clone, Copy)]
+struct QueueEntry {
+    // Stays the same as songs around it are added, removed, or moved, so clients can keep
+    // referring to the same song
+    id: u64,
+    // The song's position as of the last queue_version, None if it was only just added
+    pos: Option<usize>,
+    // The queue_version where the song was added or last moved to a different position
+    version: u64,
+}
+
 /// Allows outside classes to affect the songs that are played
 struct SongControlThread {
     _queued_music_files: Vec<std::path::PathBuf>,
+    // What we know about each song in _queued_music_files besides its path
+    _queue_entries: Vec<QueueEntry>,
+    _next_queue_id: u64,
+    // True if _queued_music_files includes files from subdirectories of the playing directory
+    _queued_recursive: bool,
     _cur_playing_index: i64,
+    // True if the current song was removed from the queue. It plays on, but _cur_playing_index
+    // points at the song before where it was, so the song that took its place plays next.
+    _cur_song_removed: bool,
+    // True if the current song started playing, false if it couldn't be played. Only songs that
+    // started are remembered as played.
+    _cur_song_started: bool,
+    // Indexes into _queued_music_files of the songs played before the current one, oldest first
+    _play_history: Vec<usize>,
+    // The order songs play in while in random mode. A permutation of the indexes into
+    // _queued_music_files, so every song plays exactly once per cycle.
+    _shuffle_order: Vec<usize>,
+    // Index into _shuffle_order of the next song to play in random mode
+    _shuffle_pos: usize,
     _player: MusicPlayer,
+    // Remembers what is in each directory and the tags of each file so we don't have to re-read them
+    _library: LibraryIndex,
+    // Watches the playing directory for changes, which are sent to MonDirThread
+    _dir_watcher: Option<RecommendedWatcher>,
+    _dir_event_sender: mpsc::Sender<notify::Result<notify::Event>>,
     _cur_settings: SettingsChanged,
-    _settings_changed_sender: Sender<SettingsChanged>,
-    _settings_changed_receiver: Receiver<SettingsChanged>,
+    _event_bus: EventBus<PlayerEvent>,
     _playback_controls_sender: std::sync::mpsc::Sender<PlaybackControls>,
+    _last_session_save: Instant,
+    // When the session first changed since it was last saved, None if it hasn't
+    _session_changed_at: Option<Instant>,
 }
 
 // Implement Send and Sync for SongControlThread
 unsafe impl Send for SongControlThread {}
 unsafe impl Sync for SongControlThread {}
 impl SongControlThread {
-    pub fn init(starting_dir: String, mut player: MusicPlayer) -> SongControlThread {
+    pub fn init(starting_dir: String, initial: InitialSettings, session: Option<&Session>, mut player: MusicPlayer,
+                dir_event_sender: mpsc::Sender<notify::Result<notify::Event>>) -> SongControlThread {
+        // Unless told otherwise, pick up the volume where the last run left off
+        let volume = initial.volume.or(session.map(|session| session.volume)).unwrap_or(1.0).clamp(0.0, 1.0);
+        player.set_volume(volume);
+
+        // The rest of the last run's settings only carry over when resuming it
+        let resumed = session.filter(|_| initial.resume != ResumeMode::Off);
+        let browsing_dir = resumed.map(|session| session.browsing_dir.clone())
+            .filter(|dir| Path::new(dir).is_dir())
+            .unwrap_or(starting_dir);
+
         // Define all our default settings
         let _cur_settings = SettingsChanged {
-            recursive: false,
-            repeat: false,
-            random: true,
+            recursive: initial.recursive.or(resumed.map(|session| session.recursive)).unwrap_or(false),
+            repeat: initial.repeat.or(resumed.map(|session| session.repeat)).unwrap_or(RepeatMode::Off),
+            random: initial.random.or(resumed.map(|session| session.random)).unwrap_or(true),
             playing_dir: String::new(),
-            browsing_dir: starting_dir.clone(),
+            browsing_dir,
             song_playing: String::new(),
-            song_time: (0, 0),
+            now_playing: None,
+            queue_len: 0,
+            queue_version: 0,
+            song_time: (0, None),
+            state: PlaybackState::Stopped,
+            volume,
+            muted: false,
         };
 
-        // create the crossbeam letting the single Controller notify as many listeners that care
+        // create the bus letting the single Controller notify as many listeners that care
         // about changes in the controller state, such as settings changing or playback duration
-        let (_settings_changed_sender, _settings_changed_receiver) = unbounded::<SettingsChanged>();
+        let _event_bus = EventBus::<PlayerEvent>::new();
 
         let pb_controls = player.get_playback_controls();
 
         SongControlThread {
             _queued_music_files: Vec::new(),
+            _queue_entries: Vec::new(),
+            _next_queue_id: 0,
+            _queued_recursive: false,
             _cur_playing_index: -1,
+            _cur_song_removed: false,
+            _cur_song_started: false,
+            _play_history: Vec::new(),
+            _shuffle_order: Vec::new(),
+            _shuffle_pos: 0,
             _player: player,
+            _library: LibraryIndex::load(),
+            _dir_watcher: None,
+            _dir_event_sender: dir_event_sender,
             _cur_settings,
-            _settings_changed_sender,
-            _settings_changed_receiver,
+            _event_bus,
             _playback_controls_sender: pb_controls,
+            _last_session_save: Instant::now(),
+            _session_changed_at: None,
+        }
+    }
+
+    /// Puts back the queue saved by a previous run and picks up the song that was playing where it
+    /// left off. If paused is true the song is left paused.
+    fn resume_session(&mut self, session: Session, paused: bool) {
+        if session.queue.is_empty() {
+            return;
+        }
+
+        let len = session.queue.len();
+        let cur_index = session.cur_index.filter(|&index| index < len);
+        let cur_song = cur_index.map(|index| session.queue[index].clone());
+        self._cur_settings.playing_dir = session.playing_dir;
+        self._queued_recursive = session.queued_recursive;
+        self._queue_entries = self.new_queue_entries(session.queue.len());
+        self._queued_music_files = session.queue;
+        self._cur_playing_index = cur_index.map_or(-1, |index| index as i64);
+        self._cur_song_removed = false;
+        self._play_history.clear();
+
+        // The saved shuffle order is only any use if it still covers the whole queue
+        let shuffle_valid = session.shuffle_order.len() == len && session.shuffle_pos <= len
+            && session.shuffle_order.iter().all(|&index| index < len);
+        if shuffle_valid {
+            self._shuffle_order = session.shuffle_order;
+            self._shuffle_pos = session.shuffle_pos;
+        } else {
+            self._shuffle_order.clear();
+            self._shuffle_pos = 0;
+        }
+
+        // Drop anything that was deleted since the last run
+        for index in (0..len).rev() {
+            if !self._queued_music_files[index].exists() {
+                self.remove_from_queue(index);
+            }
+        }
+        self.queue_changed();
+        self.dirs_changed();
+        println!("Resuming {} songs from {}", self._queued_music_files.len(), self._cur_settings.playing_dir);
+
+        // Playlists have no directory to watch
+        if Path::new(&self._cur_settings.playing_dir).is_dir() {
+            self.watch_playing_dir();
+        }
+
+        let res = match cur_song.filter(|song| song.exists()) {
+            Some(song) => match self.play_song_from(&song, session.position_secs, paused) {
+                // The song that was playing can't be played any more, so go on to the one after it
+                Err(e) if e.is_song_specific() => self.play_next_song(false),
+                res => res,
+            },
+            // The song that was playing is gone, so go on to the one after it
+            None if !paused => self.play_next_song(false),
+            None => Ok(()),
+        };
+        if let Err(e) = res {
+            eprintln!("Unable to resume playing: {}", e);
+        }
+    }
+
+    /// Captures the queue, what is playing, and the settings, so a later run can resume them
+    fn session(&self) -> Session {
+        let (queue, cur_index) = self.get_queue();
+        let position_secs = if self._cur_settings.song_playing.is_empty() {
+            0
+        } else {
+            self._cur_settings.song_time.0
+        };
+        Session {
+            browsing_dir: self._cur_settings.browsing_dir.clone(),
+            playing_dir: self._cur_settings.playing_dir.clone(),
+            queue,
+            queued_recursive: self._queued_recursive,
+            cur_index,
+            position_secs,
+            shuffle_order: self._shuffle_order.clone(),
+            shuffle_pos: self._shuffle_pos,
+            volume: self._cur_settings.volume,
+            random: self._cur_settings.random,
+            repeat: self._cur_settings.repeat,
+            recursive: self._cur_settings.recursive,
+        }
+    }
+
+    pub fn save_session(&mut self) {
+        saved_state::save_session(&self.session());
+        // The library index is only written out now and then, as it can be large
+        self._library.save();
+        self._last_session_save = Instant::now();
+        self._session_changed_at = None;
+    }
+
+    /// Saves the session and library index if the session changed a moment ago, or if it has been
+    /// a while since they were last saved
+    fn save_session_if_due(&mut self) {
+        let changes_due = self._session_changed_at.is_some_and(|changed_at| changed_at.elapsed() >= SESSION_CHANGE_SAVE_DELAY);
+        if changes_due || self._last_session_save.elapsed() >= SESSION_SAVE_INTERVAL {
+            self.save_session();
         }
     }
 
     pub fn set_random(&mut self, is_random: bool) {
+        if is_random && !self._cur_settings.random {
+            // start a fresh cycle so every song gets played once from here on
+            self._shuffle_order.clear();
+        }
         self._cur_settings.random = is_random;
-        SongControlThread::send_settings(self);
+        self.modes_changed();
     }
 
-    pub fn set_repeat_all(&mut self, is_repeat_all: bool) {
-        self._cur_settings.repeat = is_repeat_all;
-        SongControlThread::send_settings(self);
+    pub fn set_repeat_mode(&mut self, repeat_mode: RepeatMode) {
+        self._cur_settings.repeat = repeat_mode;
+        self.modes_changed();
     }
 
     pub fn set_recursive(&mut self, is_recursive: bool) {
         self._cur_settings.recursive = is_recursive;
-        SongControlThread::send_settings(self);
+        self.modes_changed();
     }
 
     pub fn pause(&mut self) {
-        self._playback_controls_sender.send(PlaybackControls::Pause(true)).unwrap();
+        self._player.pause();
+        self.sync_state();
     }
 
     pub fn stop(&mut self) {
-        self._playback_controls_sender.send(PlaybackControls::Stop(true)).unwrap();
+        self._player.stop();
+        self.sync_state();
+        self.end_cur_track();
     }
 
-    pub fn play(&mut self) {
-        self._playback_controls_sender.send(PlaybackControls::Play(true)).unwrap();
+    /// Resumes the current song if it is paused. If nothing is playing, starts the current song in
+    /// the queue over, or the next one if there isn't a current song.
+    pub fn play(&mut self) -> Result<(), PlayerError> {
+        match self._player.state() {
+            PlaybackState::Playing => Ok(()),
+            PlaybackState::Paused => {
+                self._player.resume();
+                self.sync_state();
+                Ok(())
+            }
+            PlaybackState::Stopped => {
+                match self.get_queue_index() {
+                    Some(index) => {
+                        let song = self._queued_music_files[index].clone();
+                        self.play_song(&song)
+                    }
+                    None => self.play_next_song(false),
+                }
+            }
+        }
     }
 
-    fn send_settings(&mut self) {
-        self._settings_changed_sender.send(self._cur_settings.clone()).unwrap();
+    /// Pauses if a song is playing, otherwise plays. Goes by the player rather than our settings,
+    /// as those only catch up once the player reports its new state.
+    pub fn play_pause(&mut self) -> Result<(), PlayerError> {
+        match self._player.state() {
+            PlaybackState::Playing => {
+                self.pause();
+                Ok(())
+            }
+            PlaybackState::Paused | PlaybackState::Stopped => self.play(),
+        }
     }
 
-    pub fn register_settings_listener(&mut self) -> Receiver<SettingsChanged> {
-        self._settings_changed_receiver.clone()
+    /// Sets the volume level, where 0.0 is silent and 1.0 is the song's natural volume. Values outside
+    /// that range are clamped.
+    pub fn set_volume(&mut self, volume: f32) {
+        let volume = volume.clamp(0.0, 1.0);
+        self._player.set_volume(volume);
+        self._cur_settings.volume = volume;
+        self.modes_changed();
+    }
+
+    pub fn toggle_mute(&mut self) {
+        self._cur_settings.muted = !self._cur_settings.muted;
+        self._player.set_muted(self._cur_settings.muted);
+        self.modes_changed();
+    }
+
+    /// Jumps to the specified position in the current song
+    pub fn seek(&mut self, pos: std::time::Duration) -> Result<(), PlayerError> {
+        if self._cur_settings.song_playing.is_empty() {
+            return Err(PlayerError::NothingPlaying);
+        }
+
+        // Clamp to the length of the song so listeners never see a position past the end
+        let total = self._cur_settings.song_time.1;
+        let pos_secs: u32 = match total {
+            Some(total) => pos.as_secs().min(total as u64) as u32,
+            None => pos.as_secs().try_into().unwrap_or(u32::MAX),
+        };
+        self._playback_controls_sender.send(PlaybackControls::Seek(pos)).unwrap();
+
+        // Let listeners know right away rather than waiting on the player's next update
+        self._cur_settings.song_time = (pos_secs, total);
+        self.publish(PlayerEvent::PositionChanged(pos_secs, total));
+        Ok(())
+    }
+
+    /// Jumps forward (positive) or backward (negative) the specified number of seconds in the
+    /// current song. Going back past the start goes to the start.
+    pub fn seek_by(&mut self, offset_secs: f64) -> Result<(), PlayerError> {
+        if !offset_secs.is_finite() {
+            return Ok(());
+        }
+        // Clamp before making a Duration, as a huge offset would overflow it
+        let (elapsed, total) = self._cur_settings.song_time;
+        let target = (elapsed as f64 + offset_secs).clamp(0.0, total.unwrap_or(u32::MAX) as f64);
+        self.seek(std::time::Duration::from_secs_f64(target))
+    }
+
+    /// Brings our playback state in line with the player's, letting listeners know if it changed.
+    /// Called whenever we change the player's state, so anyone asking sees the change right away.
+    fn sync_state(&mut self) {
+        let state = self._player.state();
+        if state != self._cur_settings.state {
+            self._cur_settings.state = state;
+            self.publish(PlayerEvent::StateChanged(state));
+        }
+    }
+
+    fn publish(&mut self, event: PlayerEvent) {
+        // Keep the saved session in step with what listeners are told, so a crash doesn't lose it.
+        // It is written out by save_session_if_due rather than here, as changes often come in bursts.
+        let session_changed = !matches!(event, PlayerEvent::PositionChanged(..) | PlayerEvent::Error(_) | PlayerEvent::Resync);
+        self._event_bus.publish(event);
+        if session_changed && self._session_changed_at.is_none() {
+            self._session_changed_at = Some(Instant::now());
+        }
+    }
+
+    /// Subscribes to the controller's events
+    pub fn subscribe(&mut self) -> Subscription<PlayerEvent> {
+        self._event_bus.subscribe(EVENT_QUEUE_LEN)
+    }
+
+    fn modes_changed(&mut self) {
+        self.publish(PlayerEvent::ModeChanged(self._cur_settings.modes()));
+    }
+
+    /// Updates our state from a status report sent by the player and lets listeners know
+    fn handle_playback_status(&mut self, status: PlaybackStatus) {
+        match status {
+            PlaybackStatus::PlaybackComplete(song_id) => {
+                // The user may have skipped to another song after this one finished
+                // but before we got the notification. Don't skip the new song too.
+                if self._player.is_cur_song(song_id) {
+                    println!("Song finished playing.");
+                    self.end_cur_track();
+                    if let Err(e) = self.play_next_song(true) {
+                        eprintln!("Unable to play the next song: {}", e);
+                    }
+                    // The player is still playing the finished song until told otherwise
+                    if self._cur_settings.song_playing.is_empty() {
+                        self._player.stop();
+                        self.sync_state();
+                    }
+                }
+            }
+            PlaybackStatus::PlaybackPercentage(elapsed, total) => {
+                self._cur_settings.song_time = (elapsed, total);
+                self.publish(PlayerEvent::PositionChanged(elapsed, total));
+            }
+            PlaybackStatus::PlaybackSeekFailed(reason, elapsed) => {
+                // Listeners were told about the seek as it was asked for, so take it back
+                let total = self._cur_settings.song_time.1;
+                self._cur_settings.song_time = (elapsed, total);
+                self.publish(PlayerEvent::PositionChanged(elapsed, total));
+                eprintln!("Unable to seek within the current song: {}", reason);
+                self.publish(PlayerEvent::Error(format!("Unable to seek within the current song: {}", reason)));
+            }
+        }
+        self.save_session_if_due();
+    }
+
+    /// Forgets about the current song, letting listeners know it has ended
+    fn end_cur_track(&mut self) {
+        if self._cur_settings.song_playing.is_empty() {
+            return;
+        }
+        let song = PathBuf::from(std::mem::take(&mut self._cur_settings.song_playing));
+        self._cur_settings.now_playing = None;
+        self._cur_settings.song_time = (0, None);
+        self.publish(PlayerEvent::TrackEnded(song));
     }
 
     pub fn get_browsing_dir(&self) -> String {
         self._cur_settings.browsing_dir.clone()
     }
 
-    pub fn play_browsing_dir(&mut self) -> io::Result<()> {
-        self._cur_settings.playing_dir = self._cur_settings.browsing_dir.clone();
-        match file_utils::list_music_files(&self._cur_settings.playing_dir, self._cur_settings.recursive) {
+    /// Changes the directory we are browsing, without affecting what is playing. Fails if the path
+    /// doesn't exist, isn't a directory, or has no music files or playlists in or under it.
+    pub fn set_browsing_dir(&mut self, dir: &str) -> io::Result<()> {
+        let path = std::path::Path::new(dir);
+        if !path.exists() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("Directory {} does not exist", dir)));
+        }
+        if !path.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a directory", dir)));
+        }
+        if !file_utils::contains_music(path)? {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("No supported music files found in {}", dir)));
+        }
+
+        self._cur_settings.browsing_dir = dir.to_string();
+        self.dirs_changed();
+        Ok(())
+    }
+
+    pub fn play_browsing_dir(&mut self) -> Result<(), PlayerError> {
+        let browsing_dir = self._cur_settings.browsing_dir.clone();
+        match self._library.list_music_files(&browsing_dir, self._cur_settings.recursive) {
             Err(e) => {
                 eprintln!("No music files found: {}", e);
-                return Err(e);
+                Err(e.into())
             },
-            Ok(files) => {
-                println!("Successfully read {} music files from {}", files.len(), self._cur_settings.playing_dir);
-                self._queued_music_files = files;
+            Ok(files) if files.is_empty() => {
+                // Leave whatever is currently playing alone rather than swapping in an empty queue
+                Err(io::Error::new(io::ErrorKind::NotFound,
+                    format!("No supported music files found in {}", browsing_dir)).into())
+            }
+            Ok(mut files) => {
+                println!("Successfully read {} music files from {}", files.len(), browsing_dir);
+                // Play albums in order when we aren't shuffling
+                self._library.sort_by_tags(&mut files);
+                self._queued_recursive = self._cur_settings.recursive;
+                let res = self.play_queue(browsing_dir, files);
+                self.watch_playing_dir();
+                res
             }
+        }
+    }
+
+    /// Replaces the queue with the songs in an M3U, M3U8, or PLS playlist and starts playing them
+    pub fn play_playlist(&mut self, playlist: &str) -> Result<(), PlayerError> {
+        let files = file_utils::read_playlist(Path::new(playlist))?;
+        if files.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                format!("No playable music files listed in {}", playlist)).into());
+        }
+        println!("Successfully read {} music files from {}", files.len(), playlist);
+
+        // Playlists are curated, so there's no directory to watch for new songs
+        self._dir_watcher = None;
+        self._queued_recursive = false;
+        self.play_queue(playlist.to_string(), files)
+    }
+
+    /// Saves the queue, in sequential order, as an M3U8 playlist
+    pub fn save_queue(&mut self, playlist: &str) -> io::Result<()> {
+        let songs: Vec<(PathBuf, Option<u32>, String)> = self._queued_music_files.iter()
+            .map(|song| match self._library.tags(song) {
+                Some(tags) => (song.clone(), tags.duration, tags.display_name()),
+                None => {
+                    let name = song.file_stem().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+                    (song.clone(), None, name)
+                }
+            })
+            .collect();
+        file_utils::write_m3u8(Path::new(playlist), &songs)?;
+        println!("Saved {} songs to {}", songs.len(), playlist);
+        Ok(())
+    }
+
+    /// Replaces the queue with the specified songs and starts playing them
+    /// # Parameters
+    /// source = The directory or playlist the songs came from, reported as the playing directory
+    /// files = The songs to queue up, in sequential order
+    fn play_queue(&mut self, source: String, files: Vec<PathBuf>) -> Result<(), PlayerError> {
+        self._cur_settings.playing_dir = source;
+        self._queue_entries = self.new_queue_entries(files.len());
+        self._queued_music_files = files;
+        self._cur_playing_index = -1;
+        self._cur_song_removed = false;
+        self._play_history.clear();
+        self._shuffle_order.clear();
+        self.queue_changed();
+        self.dirs_changed();
+        self.play_next_song(false)
+    }
+
+
+    /// Starts watching the playing directory for music files being added or removed, replacing
+    /// whatever directory we were watching before
+    fn watch_playing_dir(&mut self) {
+        // Dropping the old watcher stops it
+        self._dir_watcher = None;
+
+        let recursive_mode = if self._queued_recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
         };
-        self.play_next_song();
+        let watch_res = notify::recommended_watcher(self._dir_event_sender.clone()).and_then(|mut watcher| {
+            watcher.watch(Path::new(&self._cur_settings.playing_dir), recursive_mode)?;
+            Ok(watcher)
+        });
+        match watch_res {
+            Ok(watcher) => self._dir_watcher = Some(watcher),
+            Err(e) => eprintln!("Unable to watch {} for changes: {}", self._cur_settings.playing_dir, e),
+        }
+    }
+
+    /// Called when a file or directory shows up in the playing directory. Queues any new music
+    /// files. Returns true if the queue changed.
+    fn path_added(&mut self, path: &Path) -> bool {
+        if file_utils::is_excluded(path) {
+            return false;
+        }
+        let new_files: Vec<PathBuf> = if path.is_dir() {
+            // Subdirectories only matter when we are playing recursively
+            if !self._queued_recursive {
+                return false;
+            }
+            match self._library.list_music_files(&path.to_string_lossy(), true) {
+                Ok(files) => files,
+                Err(e) => {
+                    eprintln!("Unable to read new directory {}: {}", path.display(), e);
+                    return false;
+                }
+            }
+        } else if file_utils::is_supported_audio_file(path) {
+            vec![path.to_path_buf()]
+        } else {
+            return false;
+        };
+
+        let new_files: Vec<PathBuf> = new_files.into_iter()
+            .filter(|file| !self._queued_music_files.contains(file))
+            .collect();
+        if new_files.is_empty() {
+            return false;
+        }
+        for file in &new_files {
+            println!("Queueing new music file: {}", file.display());
+        }
+        self.insert_into_queue(self._queued_music_files.len(), new_files, false);
+        true
+    }
+
+    /// Called when a file or directory disappears from the playing directory. Removes it, or
+    /// everything that was under it, from the queue. Returns true if the queue changed.
+    fn path_removed(&mut self, path: &Path) -> bool {
+        let mut changed = false;
+        // Walk backwards so removing a song doesn't shift the songs we have yet to check
+        for index in (0..self._queued_music_files.len()).rev() {
+            if self._queued_music_files[index].starts_with(path) {
+                println!("Removing vanished music file: {}", self._queued_music_files[index].display());
+                self.remove_from_queue(index);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Gets the music files at the specified path. A directory gives all the music files in it (and
+    /// its subdirectories when recursive is on) in album order, a playlist gives the songs listed in
+    /// it, and a music file gives just itself.
+    fn music_files_at(&mut self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        if !path.exists() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} does not exist", path.display())));
+        }
+        if file_utils::is_playlist_file(path) {
+            return file_utils::read_playlist(path);
+        }
+        if !path.is_dir() {
+            if !file_utils::is_supported_audio_file(path) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("{} is not a supported music file", path.display())));
+            }
+            return Ok(vec![path.to_path_buf()]);
+        }
+
+        let mut files = self._library.list_music_files(&path.to_string_lossy(), self._cur_settings.recursive)?;
+        if files.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                format!("No supported music files found in {}", path.display())));
+        }
+        self._library.sort_by_tags(&mut files);
+        Ok(files)
+    }
+
+    /// Adds a music file, or all the music files in a directory, to the queue. If play_next is true
+    /// they go right after the current song (and play next in random mode too), otherwise they go
+    /// on the end. Returns the number of songs added.
+    pub fn enqueue(&mut self, path: &str, play_next: bool) -> io::Result<usize> {
+        let files = self.music_files_at(Path::new(path))?;
+        let count = files.len();
+        let index = if play_next {
+            ((self._cur_playing_index + 1) as usize).min(self._queued_music_files.len())
+        } else {
+            self._queued_music_files.len()
+        };
+        self.insert_into_queue(index, files, play_next);
+        Ok(count)
+    }
+
+    /// Removes the song at the specified position in the queue. If it is the current song, it keeps
+    /// playing.
+    pub fn remove_queued(&mut self, index: usize) -> io::Result<()> {
+        self.check_queue_index(index)?;
+        self.remove_from_queue(index);
         Ok(())
     }
 
+    /// Moves the song at one position in the queue to another
+    pub fn move_queued(&mut self, from: usize, to: usize) -> io::Result<()> {
+        self.check_queue_index(from)?;
+        self.check_queue_index(to)?;
+        let file = self._queued_music_files.remove(from);
+        self._queued_music_files.insert(to, file);
+        let entry = self._queue_entries.remove(from);
+        self._queue_entries.insert(to, entry);
 
-    fn play_next_song(&mut self) {
-        if self._cur_settings.random {
-            // play a random song
+        // Everything between the two positions shifts one spot toward where the song came from
+        let move_index = |queued: usize| -> usize {
+            if queued == from {
+                to
+            } else if from < to && queued > from && queued <= to {
+                queued - 1
+            } else if from > to && queued >= to && queued < from {
+                queued + 1
+            } else {
+                queued
+            }
+        };
+        if self._cur_playing_index >= 0 {
+            self._cur_playing_index = move_index(self._cur_playing_index as usize) as i64;
+        }
+        self.remap_queue_indexes(|queued| Some(move_index(queued)));
+        self.queue_changed();
+        Ok(())
+    }
+
+    /// Stops playback and empties the queue
+    pub fn clear_queue(&mut self) {
+        self.stop();
+        self._queued_music_files.clear();
+        self._queue_entries.clear();
+        self._cur_playing_index = -1;
+        self._cur_song_removed = false;
+        self._play_history.clear();
+        self._shuffle_order.clear();
+        self._shuffle_pos = 0;
+        self.queue_changed();
+    }
+
+    /// Gets the songs in the queue, in sequential order, along with the position of the current song
+    pub fn get_queue(&self) -> (Vec<PathBuf>, Option<usize>) {
+        (self._queued_music_files.clone(), self.get_queue_index())
+    }
+
+    /// Jumps to the song at the specified position in the queue and plays it
+    pub fn play_queued(&mut self, index: usize) -> Result<(), PlayerError> {
+        self.check_queue_index(index)?;
+        if let Some(cur_index) = self.get_queue_index().filter(|_| self._cur_song_started) {
+            self.remember_played(cur_index);
+        }
+        self._cur_playing_index = index as i64;
+        self._cur_song_removed = false;
+        let song = self._queued_music_files[index].clone();
+        self.play_song(&song)
+    }
+
+    /// Gets the current song along with its position and id in the queue, if it is queued
+    pub fn current_queued(&self) -> Option<(PathBuf, usize, u64)> {
+        let index = self.get_queue_index()?;
+        Some((self._queued_music_files[index].clone(), index, self._queue_entries[index].id))
+    }
+
+    /// Gets the id of each song in the queue, in sequential order. A song keeps its id for as long
+    /// as it is queued.
+    pub fn get_queue_ids(&self) -> Vec<u64> {
+        self._queue_entries.iter().map(|entry| entry.id).collect()
+    }
+
+    /// Gets the position in the queue of the song with the specified id, if it is still queued
+    pub fn queue_index_of(&self, id: u64) -> Option<usize> {
+        self._queue_entries.iter().position(|entry| entry.id == id)
+    }
+
+    /// Gets the positions in the queue of the songs that were added or moved since the specified
+    /// queue_version
+    pub fn queue_changes_since(&self, version: u64) -> Vec<usize> {
+        self._queue_entries.iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.version > version)
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Makes entries, with new ids, for songs being added to the queue
+    fn new_queue_entries(&mut self, count: usize) -> Vec<QueueEntry> {
+        let entries = (self._next_queue_id..self._next_queue_id + count as u64)
+            .map(|id| QueueEntry { id, pos: None, version: 0 })
+            .collect();
+        self._next_queue_id += count as u64;
+        entries
+    }
+
+    /// Gets the position of the current song in the queue, if there is one. A song that was
+    /// removed from the queue has no position, even while it plays on.
+    fn get_queue_index(&self) -> Option<usize> {
+        usize::try_from(self._cur_playing_index).ok()
+            .filter(|&index| index < self._queued_music_files.len() && !self._cur_song_removed)
+    }
+
+    /// Gets the music files directly in the specified directory, in album order
+    pub fn music_files(&mut self, dir: &str) -> io::Result<Vec<PathBuf>> {
+        let mut files = self._library.list_music_files(dir, false)?;
+        self._library.sort_by_tags(&mut files);
+        Ok(files)
+    }
+
+    /// Gets the tags of the specified songs from the library index. Songs that aren't in the index
+    /// get None.
+    pub fn tags(&self, songs: &[PathBuf]) -> Vec<Option<TrackMetadata>> {
+        songs.iter().map(|song| self._library.tags(song).cloned()).collect()
+    }
+
+    fn check_queue_index(&self, index: usize) -> io::Result<()> {
+        if index >= self._queued_music_files.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("No song at position {}, the queue has {} songs", index, self._queued_music_files.len())));
+        }
+        Ok(())
+    }
+
+    /// Inserts songs into the queue at the specified index. In random mode they are shuffled in
+    /// among the songs that haven't played yet this cycle, or put first in line if play_next is true.
+    fn insert_into_queue(&mut self, index: usize, files: Vec<PathBuf>, play_next: bool) {
+        let count = files.len();
+        self._queued_music_files.splice(index..index, files);
+        let entries = self.new_queue_entries(count);
+        self._queue_entries.splice(index..index, entries);
+        if self._cur_playing_index >= index as i64 {
+            self._cur_playing_index += count as i64;
+        }
+        self.remap_queue_indexes(|queued| Some(if queued >= index { queued + count } else { queued }));
+
+        if !self._shuffle_order.is_empty() {
             let mut rng = rand::thread_rng();
-            let random_index = rng.gen_range(0..self._queued_music_files.len());
+            for (offset, new_index) in (index..index + count).enumerate() {
+                let insert_pos = if play_next {
+                    self._shuffle_pos + offset
+                } else {
+                    rng.gen_range(self._shuffle_pos..=self._shuffle_order.len())
+                };
+                self._shuffle_order.insert(insert_pos, new_index);
+            }
+        }
+        self.queue_changed();
+    }
+
+    /// Removes the song at the specified index from the queue, keeping the current song, play
+    /// history, and shuffle order pointing at the same songs they did before
+    fn remove_from_queue(&mut self, index: usize) {
+        self._queued_music_files.remove(index);
+        self._queue_entries.remove(index);
+
+        // If the current song is the one removed it keeps playing, but the song after it is the
+        // next to play
+        if self._cur_playing_index == index as i64 {
+            self._cur_song_removed = true;
+        }
+        if self._cur_playing_index >= index as i64 {
+            self._cur_playing_index -= 1;
+        }
+        self.remap_queue_indexes(|queued| match queued {
+            _ if queued == index => None,
+            _ if queued > index => Some(queued - 1),
+            _ => Some(queued),
+        });
+        self.queue_changed();
+    }
+
+    /// Updates the play history and shuffle order after songs have moved around in the queue.
+    /// The map gives each song's new index from its old one, or None if the song was removed.
+    fn remap_queue_indexes(&mut self, map: impl Fn(usize) -> Option<usize>) {
+        self._play_history = self._play_history.iter().filter_map(|&played| map(played)).collect();
+
+        let mut shuffle_order = Vec::with_capacity(self._shuffle_order.len());
+        let mut shuffle_pos = self._shuffle_pos;
+        for (pos, &queued) in self._shuffle_order.iter().enumerate() {
+            match map(queued) {
+                Some(new_index) => shuffle_order.push(new_index),
+                // One less song ahead of where we are in the shuffle
+                None if pos < self._shuffle_pos => shuffle_pos -= 1,
+                None => {}
+            }
+        }
+        self._shuffle_order = shuffle_order;
+        self._shuffle_pos = shuffle_pos;
+    }
+
+    /// Lets listeners know they need to fetch the queue again
+    fn queue_changed(&mut self) {
+        self._cur_settings.queue_len = self._queued_music_files.len();
+        self._cur_settings.queue_version += 1;
+        // Note which songs are new or have shifted, for clients that only fetch what changed
+        let version = self._cur_settings.queue_version;
+        for (index, entry) in self._queue_entries.iter_mut().enumerate() {
+            if entry.pos != Some(index) {
+                entry.pos = Some(index);
+                entry.version = version;
+            }
+        }
+        self.publish(PlayerEvent::QueueChanged(self._cur_settings.queue_len, self._cur_settings.queue_version));
+    }
+
+    fn dirs_changed(&mut self) {
+        self.publish(PlayerEvent::DirChanged(self._cur_settings.browsing_dir.clone(), self._cur_settings.playing_dir.clone()));
+    }
+
+    /// Throws away the library index and re-reads the browsing directory from scratch. Returns
+    /// the number of music files found.
+    pub fn rescan(&mut self) -> io::Result<usize> {
+        let browsing_dir = self._cur_settings.browsing_dir.clone();
+        self._library.rescan(&browsing_dir, self._cur_settings.recursive)
+    }
+
+    /// Plays the song after the current one, in either random or sequential order. Songs that can't
+    /// be played are skipped, until every song in the queue has been tried.
+    /// # Parameters
+    /// song_finished = True if the current song played all the way through, false if the user asked
+    ///                 to skip it. A song that finished on its own plays again in RepeatMode::One.
+    fn play_next_song(&mut self, song_finished: bool) -> Result<(), PlayerError> {
+        let mut res = self.advance_and_play(song_finished);
+        let mut attempts: usize = 1;
+        while let Err(e) = &res {
+            if !e.is_song_specific() || attempts >= self._queued_music_files.len() {
+                break;
+            }
+            println!("Skipping unplayable song");
+            attempts += 1;
+            res = self.advance_and_play(false);
+        }
+        res
+    }
+
+    /// Moves on to the song after the current one and plays it, without skipping it if it can't be
+    /// played. See play_next_song.
+    fn advance_and_play(&mut self, song_finished: bool) -> Result<(), PlayerError> {
+        if self._queued_music_files.is_empty() {
+            println!("No songs to play.");
+            return Ok(());
+        }
+
+        let prev_index = self._cur_playing_index;
+        let has_cur_song = self.get_queue_index().is_some();
+        let played_index = self.get_queue_index().filter(|_| self._cur_song_started);
+        if song_finished && has_cur_song && self._cur_settings.repeat == RepeatMode::One {
+            let song_to_play = self._queued_music_files[prev_index as usize].clone();
+            println!("Repeating song: {}", song_to_play.display());
+            return self.play_song(&song_to_play);
+        }
+
+        // Repeating one song only applies to songs that finish on their own. If the user skips
+        // ahead, the queue wraps around just like repeating all.
+        let repeat_queue = self._cur_settings.repeat != RepeatMode::Off;
+        if self._cur_settings.random {
+            // play the next song from the shuffled order
+            if self._shuffle_order.len() != self._queued_music_files.len() {
+                // we haven't shuffled this queue yet
+                self.reshuffle();
+            } else if self._shuffle_pos >= self._shuffle_order.len() {
+                if repeat_queue {
+                    println!("Every song played. Reshuffling and starting over.");
+                    self.reshuffle();
+                } else {
+                    println!("Every song played. No more songs to play.");
+                    return Ok(());
+                }
+            }
+            let random_index = self._shuffle_order[self._shuffle_pos];
+            self._shuffle_pos += 1;
             self._cur_playing_index = random_index.try_into().unwrap();
+            self._cur_song_removed = false;
             println!("Playing next random song at index {}: {}", self._cur_playing_index, self._queued_music_files[self._cur_playing_index as usize].display());
         } else {
             // play the next song in the queue
 
             // figure out the index of the next song to play
-            let next_index: usize;
-            if -1 == self._cur_playing_index{
+            let next_index: usize = if -1 == self._cur_playing_index {
                 // -1 indicates we aren't playing a song yet
-                next_index = 0;
+                0
             } else {
-                next_index = self._cur_playing_index as usize + 1;
-            }
+                self._cur_playing_index as usize + 1
+            };
             self._cur_playing_index = next_index.try_into().unwrap();
 
             if next_index >= self._queued_music_files.len() {
-                if self._cur_settings.repeat {
+                if repeat_queue {
                     self._cur_playing_index = 0;
                     println!("Last song played. Starting over with: {}", self._queued_music_files[0].display());
                 } else {
                     println!("End of playlist. No more songs to play.");
-                    return;
+                    // Stay on the last song so anything queued after it plays next
+                    self._cur_playing_index = prev_index;
+                    return Ok(());
                 }
             }
             else {
                 println!("Playing next song: {}", self._queued_music_files[next_index].display());
             }
+            self._cur_song_removed = false;
+        }
+        // remember what we were playing so prior_track can come back to it
+        if let Some(played_index) = played_index {
+            self.remember_played(played_index);
         }
+
         // actually play the song, regardless of whether it was randomly or sequentially chosen
         let song_to_play = self._queued_music_files[self._cur_playing_index as usize].clone();
-        self.play_song(&song_to_play);
+        self.play_song(&song_to_play)
     }
 
+    /// Adds a song to the history prior_track walks back through
+    fn remember_played(&mut self, index: usize) {
+        self._play_history.push(index);
+        if self._play_history.len() > MAX_PLAY_HISTORY {
+            self._play_history.remove(0);
+        }
+    }
+
+    /// Starts a new random cycle through the queue. The song currently playing won't be first in the
+    /// new order, otherwise it would play twice in a row.
+    fn reshuffle(&mut self) {
+        let mut rng = rand::thread_rng();
+        let mut order: Vec<usize> = (0..self._queued_music_files.len()).collect();
+        order.shuffle(&mut rng);
+
+        if order.len() > 1 && order[0] as i64 == self._cur_playing_index {
+            let swap_index = rng.gen_range(1..order.len());
+            order.swap(0, swap_index);
+        }
+
+        self._shuffle_order = order;
+        self._shuffle_pos = 0;
+    }
 
-    pub fn play_song(&mut self, song: &std::path::PathBuf) {
-        self._cur_settings.song_playing = song.to_str().unwrap().to_string();
-        self._cur_settings.song_time = (0, 0);
+    /// Goes back a song. If we are more than a few seconds into the current song it is restarted
+    /// instead. In random mode, "back" means the song that actually played before this one.
+    pub fn prior_track(&mut self) -> Result<(), PlayerError> {
+        if self._cur_song_removed {
+            return self.prior_track_after_removal();
+        }
+        let Some(cur_index) = self.get_queue_index() else {
+            println!("No song has been played yet, nothing to go back to.");
+            return Ok(());
+        };
 
-        match self._player.play_music_file(song) {
+        let prior_index: usize = if self._cur_settings.song_time.0 > PRIOR_TRACK_RESTART_SECS {
+            cur_index
+        } else if self._cur_settings.random {
+            // Walk back through what was really played. Once we run out of history, just restart
+            // the current song.
+            match self._play_history.pop() {
+                Some(prior_index) => {
+                    // The song we are leaving plays again next, if it came from the shuffle order
+                    if self._shuffle_pos > 0 && self._shuffle_order[self._shuffle_pos - 1] == cur_index {
+                        self._shuffle_pos -= 1;
+                    }
+                    prior_index
+                }
+                None => cur_index,
+            }
+        } else if cur_index > 0 {
+            cur_index - 1
+        } else if self._cur_settings.repeat != RepeatMode::Off {
+            // Going back from the first song wraps around to the last, just like going forward
+            // from the last song wraps around to the first
+            self._queued_music_files.len() - 1
+        } else {
+            cur_index
+        };
+
+        if prior_index == cur_index {
+            println!("Restarting song: {}", self._queued_music_files[cur_index].display());
+        } else {
+            println!("Playing prior song: {}", self._queued_music_files[prior_index].display());
+        }
+        self._cur_playing_index = prior_index.try_into().unwrap();
+        let song_to_play = self._queued_music_files[prior_index].clone();
+        self.play_song(&song_to_play)
+    }
+
+    /// Goes back a song when the current one has been removed from the queue, so can't be
+    /// restarted. Goes to the song that played before it in random mode, or the song before where
+    /// it was otherwise.
+    fn prior_track_after_removal(&mut self) -> Result<(), PlayerError> {
+        let prior_index = if self._cur_settings.random {
+            self._play_history.pop()
+        } else {
+            usize::try_from(self._cur_playing_index).ok()
+        };
+        let Some(prior_index) = prior_index else {
+            println!("Nothing to go back to.");
+            return Ok(());
+        };
+        println!("Playing prior song: {}", self._queued_music_files[prior_index].display());
+        self._cur_playing_index = prior_index.try_into().unwrap();
+        self._cur_song_removed = false;
+        let song_to_play = self._queued_music_files[prior_index].clone();
+        self.play_song(&song_to_play)
+    }
+
+    /// Starts the specified song from the beginning
+    fn play_song(&mut self, song: &std::path::PathBuf) -> Result<(), PlayerError> {
+        self.play_song_from(song, 0, false)
+    }
+
+    /// Plays the specified song starting the specified number of seconds in. If paused is true the
+    /// song is left paused there. If the song can't be played, listeners are told why.
+    fn play_song_from(&mut self, song: &std::path::PathBuf, start_secs: u32, paused: bool) -> Result<(), PlayerError> {
+        // Whatever was playing is replaced, even if the new song turns out to be unplayable
+        self.end_cur_track();
+        let res = self.start_song(song, start_secs, paused);
+        self._cur_song_started = res.is_ok();
+        if let Err(e) = &res {
+            eprintln!("Failed to play file: {}", e);
+            self.publish(PlayerEvent::Error(e.to_string()));
+        }
+        res
+    }
+
+    fn start_song(&mut self, song: &std::path::PathBuf, start_secs: u32, paused: bool) -> Result<(), PlayerError> {
+        let song_playing = song.to_str()
+            .ok_or_else(|| PlayerError::NonUtf8Path(song.clone()))?
+            .to_string();
+        let mut now_playing = match self._library.read_tags(song) {
+            Ok(track_metadata) => Some(track_metadata),
             Err(e) => {
-                eprintln!("Failed to play file: {}", e);
+                eprintln!("Unable to read tags from {}: {}", song.display(), e);
+                None
             }
-            Ok(_) => {}
         };
+
+        let known_duration = now_playing.as_ref().and_then(|tags| tags.duration);
+        if let Err(e) = self._player.play_music_file(song, Duration::from_secs(start_secs.into()), paused, known_duration) {
+            self.sync_state();
+            return Err(e);
+        }
+
+        // For songs that don't record their length, the player works it out
+        let total = self._player.cur_duration();
+        if let (Some(tags), Some(duration)) = (now_playing.as_mut(), total) {
+            if tags.duration.is_none() {
+                tags.duration = Some(duration);
+                self._library.set_duration(song, duration);
+            }
+        }
+        self._cur_settings.song_playing = song_playing;
+        self._cur_settings.now_playing = now_playing.clone();
+        self._cur_settings.song_time = (start_secs, total);
+        self.publish(PlayerEvent::TrackStarted(song.clone(), now_playing));
+        self.sync_state();
+        Ok(())
     }
 }
 
+/// Cloning a Controller gives another handle to the same player, so the terminal UI and the
+/// remote control interfaces can each have their own
+#[derive(Clone)]
 pub struct Controller {
-    _mon_song_thread: MonSongThread,
+    _mon_song_thread: Arc<MonSongThread>,
+    _mon_dir_thread: Arc<MonDirThread>,
     _song_ctrl_thread: Arc<Mutex<SongControlThread>>,
 }
 
 impl Controller {
-    pub fn init(starting_dir: String) -> Controller {
+    pub fn init(starting_dir: String, initial: InitialSettings) -> Controller {
         let (notifier, listener) = std::sync::mpsc::channel::<PlaybackStatus>();        
         let player: MusicPlayer = MusicPlayer::init(notifier);
+        let (dir_event_notifier, dir_event_listener) = std::sync::mpsc::channel::<notify::Result<notify::Event>>();
 
-        let sct: SongControlThread = SongControlThread::init(starting_dir.clone(), player);
+        // Pick up where the last run left off, if we are asked to
+        let session = saved_state::load_session();
+        let resume = initial.resume;
+        let mut sct: SongControlThread = SongControlThread::init(starting_dir.clone(), initial, session.as_ref(), player, dir_event_notifier);
+        if let Some(session) = session.filter(|_| resume != ResumeMode::Off) {
+            sct.resume_session(session, resume == ResumeMode::Paused);
+        }
 
         let _song_ctrl_thread: Arc<Mutex<SongControlThread>> = Arc::new(Mutex::new(sct));
         let _mon_song_thread: MonSongThread = MonSongThread::init(Arc::clone(&_song_ctrl_thread), listener);
+        let _mon_dir_thread: MonDirThread = MonDirThread::init(Arc::clone(&_song_ctrl_thread), dir_event_listener);
 
         Controller {
-            _mon_song_thread,
+            _mon_song_thread: Arc::new(_mon_song_thread),
+            _mon_dir_thread: Arc::new(_mon_dir_thread),
             _song_ctrl_thread,
         }
     }
@@ -222,17 +1261,48 @@ impl Controller {
         self._song_ctrl_thread.lock().unwrap().set_random(is_random);
     }
 
-    pub fn set_repeat_all(&mut self, is_repeat_all: bool) {
-        self._song_ctrl_thread.lock().unwrap().set_repeat_all(is_repeat_all);
+    /// Saves the queue, what is playing, and the settings so the next run can resume them, along
+    /// with the library index
+    pub fn save_session(&mut self) {
+        self._song_ctrl_thread.lock().unwrap().save_session();
+    }
+
+    /// Saves the session if it changed a moment ago, or if it has been a while since it was last
+    /// saved. Called regularly so changes are saved even when no song is playing.
+    pub fn save_session_if_due(&mut self) {
+        self._song_ctrl_thread.lock().unwrap().save_session_if_due();
+    }
+
+    pub fn set_repeat_mode(&mut self, repeat_mode: RepeatMode) {
+        self._song_ctrl_thread.lock().unwrap().set_repeat_mode(repeat_mode);
+    }
+
+    /// Steps to the next repeat mode (off, all, one, then back to off) and returns the new mode
+    pub fn cycle_repeat_mode(&mut self) -> RepeatMode {
+        let mut song_ctrl = self._song_ctrl_thread.lock().unwrap();
+        let repeat_mode = song_ctrl._cur_settings.repeat.next();
+        song_ctrl.set_repeat_mode(repeat_mode);
+        repeat_mode
     }
 
     pub fn set_recursive(&mut self, is_recursive: bool) {
         self._song_ctrl_thread.lock().unwrap().set_recursive(is_recursive);
     }
 
-    pub fn register_settings_listener(&mut self) -> Receiver<SettingsChanged> {
+    /// Subscribes to the controller's events. Every listener gets its own copy of each event, and
+    /// drop the subscription to stop listening.
+    pub fn subscribe(&mut self) -> Subscription<PlayerEvent> {
         println!("Controller: About to register");
-        self._song_ctrl_thread.lock().unwrap().register_settings_listener()
+        self._song_ctrl_thread.lock().unwrap().subscribe()
+    }
+
+    pub fn get_browsing_dir(&self) -> String {
+        self._song_ctrl_thread.lock().unwrap().get_browsing_dir()
+    }
+
+    /// Gets the full current state, for listeners that need more than what an event carries
+    pub fn snapshot(&self) -> SettingsChanged {
+        self._song_ctrl_thread.lock().unwrap()._cur_settings.clone()
     }
 
     pub fn get_available_dirs(&self) -> io::Result<Vec<String>> {
@@ -243,16 +1313,117 @@ impl Controller {
         }
     }
 
-    pub fn play_browsing_dir(&mut self) -> io::Result<()> {
+    /// Changes the directory we are browsing. If play is true, the music in the new directory
+    /// starts playing right away. Fails if the directory has no music files or playlists in or
+    /// under it, either way.
+    pub fn set_browsing_dir(&mut self, dir: &str, play: bool) -> Result<(), PlayerError> {
+        let mut song_ctrl = self._song_ctrl_thread.lock().unwrap();
+        song_ctrl.set_browsing_dir(dir)?;
+        if play {
+            song_ctrl.play_browsing_dir()?;
+        }
+        Ok(())
+    }
+
+    pub fn play_browsing_dir(&mut self) -> Result<(), PlayerError> {
         self._song_ctrl_thread.lock().unwrap().play_browsing_dir()
     }
 
-    pub fn play_song(&mut self, song: &std::path::PathBuf) {
-        self._song_ctrl_thread.lock().unwrap().play_song(song)
+    /// Replaces the queue with the songs in an M3U, M3U8, or PLS playlist and starts playing them
+    pub fn play_playlist(&mut self, playlist: &str) -> Result<(), PlayerError> {
+        self._song_ctrl_thread.lock().unwrap().play_playlist(playlist)
+    }
+
+    /// Saves the queue, in sequential order, as an M3U8 playlist
+    pub fn save_queue(&mut self, playlist: &str) -> io::Result<()> {
+        self._song_ctrl_thread.lock().unwrap().save_queue(playlist)
+    }
+
+    /// Gets the names of the playlist files in the browsing directory
+    pub fn get_available_playlists(&self) -> io::Result<Vec<String>> {
+        file_utils::playlist_files(&self._song_ctrl_thread.lock().unwrap().get_browsing_dir())
+    }
+
+    /// Adds a music file, or all the music files in a directory, to the end of the queue. Returns
+    /// the number of songs added.
+    pub fn enqueue(&mut self, path: &str) -> io::Result<usize> {
+        self._song_ctrl_thread.lock().unwrap().enqueue(path, false)
+    }
+
+    /// Adds a music file, or all the music files in a directory, right after the current song so
+    /// they play next. Returns the number of songs added.
+    pub fn enqueue_next(&mut self, path: &str) -> io::Result<usize> {
+        self._song_ctrl_thread.lock().unwrap().enqueue(path, true)
+    }
+
+    /// Removes the song at the specified position in the queue
+    pub fn remove_queued(&mut self, index: usize) -> io::Result<()> {
+        self._song_ctrl_thread.lock().unwrap().remove_queued(index)
+    }
+
+    /// Moves the song at one position in the queue to another
+    pub fn move_queued(&mut self, from: usize, to: usize) -> io::Result<()> {
+        self._song_ctrl_thread.lock().unwrap().move_queued(from, to)
+    }
+
+    /// Stops playback and empties the queue
+    pub fn clear_queue(&mut self) {
+        self._song_ctrl_thread.lock().unwrap().clear_queue();
+    }
+
+    /// Gets the songs in the queue, in sequential order, along with the position of the current song
+    pub fn get_queue(&self) -> (Vec<PathBuf>, Option<usize>) {
+        self._song_ctrl_thread.lock().unwrap().get_queue()
+    }
+
+    /// Gets the current song along with its position and id in the queue, if it is queued. They
+    /// are fetched together so they agree, even while other clients are changing the queue.
+    pub fn current_queued(&self) -> Option<(PathBuf, usize, u64)> {
+        self._song_ctrl_thread.lock().unwrap().current_queued()
+    }
+
+    /// Gets the id of each song in the queue, in sequential order. A song keeps its id for as long
+    /// as it is queued, even as songs around it are added, removed, or moved.
+    pub fn get_queue_ids(&self) -> Vec<u64> {
+        self._song_ctrl_thread.lock().unwrap().get_queue_ids()
+    }
+
+    /// Gets the position in the queue of the song with the specified id, if it is still queued
+    pub fn queue_index_of(&self, id: u64) -> Option<usize> {
+        self._song_ctrl_thread.lock().unwrap().queue_index_of(id)
+    }
+
+    /// Gets the positions in the queue of the songs that were added or moved since the specified
+    /// queue_version, for clients that only fetch what changed
+    pub fn queue_changes_since(&self, version: u64) -> Vec<usize> {
+        self._song_ctrl_thread.lock().unwrap().queue_changes_since(version)
     }
 
-    pub fn play(&mut self) {
-        self._song_ctrl_thread.lock().unwrap().play();
+    /// Jumps to the song at the specified position in the queue and plays it
+    pub fn play_queued(&mut self, index: usize) -> Result<(), PlayerError> {
+        self._song_ctrl_thread.lock().unwrap().play_queued(index)
+    }
+
+    /// Gets the music files directly in the specified directory, in album order
+    pub fn music_files(&self, dir: &str) -> io::Result<Vec<PathBuf>> {
+        self._song_ctrl_thread.lock().unwrap().music_files(dir)
+    }
+
+    /// Gets the tags of the specified songs from the library index. Songs that aren't in the index
+    /// get None.
+    pub fn tags(&self, songs: &[PathBuf]) -> Vec<Option<TrackMetadata>> {
+        self._song_ctrl_thread.lock().unwrap().tags(songs)
+    }
+
+    /// Rebuilds the library index for the browsing directory from scratch. Returns the number of
+    /// music files found.
+    pub fn rescan(&mut self) -> io::Result<usize> {
+        self._song_ctrl_thread.lock().unwrap().rescan()
+    }
+
+    /// Resumes the current song if it is paused, or starts playing the queue if nothing is playing
+    pub fn play(&mut self) -> Result<(), PlayerError> {
+        self._song_ctrl_thread.lock().unwrap().play()
     }
 
     pub fn pause(&mut self) {
@@ -263,7 +1434,370 @@ impl Controller {
         self._song_ctrl_thread.lock().unwrap().stop();
     }
 
-    pub fn next(&mut self) {
-        self._song_ctrl_thread.lock().unwrap().play_next_song();
+    pub fn play_pause(&mut self) -> Result<(), PlayerError> {
+        self._song_ctrl_thread.lock().unwrap().play_pause()
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self._song_ctrl_thread.lock().unwrap().set_volume(volume);
+    }
+
+    pub fn volume_up(&mut self) {
+        let mut song_ctrl = self._song_ctrl_thread.lock().unwrap();
+        let volume = song_ctrl._cur_settings.volume + VOLUME_STEP;
+        song_ctrl.set_volume(volume);
+    }
+
+    pub fn volume_down(&mut self) {
+        let mut song_ctrl = self._song_ctrl_thread.lock().unwrap();
+        let volume = song_ctrl._cur_settings.volume - VOLUME_STEP;
+        song_ctrl.set_volume(volume);
+    }
+
+    pub fn toggle_mute(&mut self) {
+        self._song_ctrl_thread.lock().unwrap().toggle_mute();
+    }
+
+    /// Jumps to the specified position in the current song. Fails if no song is playing.
+    pub fn seek(&mut self, pos: std::time::Duration) -> Result<(), PlayerError> {
+        self._song_ctrl_thread.lock().unwrap().seek(pos)
+    }
+
+    /// Jumps forward (positive) or backward (negative) the specified number of seconds in the
+    /// current song. Fails if no song is playing.
+    pub fn seek_by(&mut self, offset_secs: f64) -> Result<(), PlayerError> {
+        self._song_ctrl_thread.lock().unwrap().seek_by(offset_secs)
+    }
+
+    /// Skips to the next song, skipping past any that can't be played
+    pub fn next(&mut self) -> Result<(), PlayerError> {
+        self._song_ctrl_thread.lock().unwrap().play_next_song(false)
+    }
+
+    pub fn prior_track(&mut self) -> Result<(), PlayerError> {
+        self._song_ctrl_thread.lock().unwrap().prior_track()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+    use std::sync::OnceLock;
+    use super::*;
+
+    /// Keeps the sessions and library indexes the tests save out of the user's own
+    fn use_scratch_dirs() {
+        static SCRATCH_DIR: OnceLock<PathBuf> = OnceLock::new();
+        SCRATCH_DIR.get_or_init(|| {
+            let dir = std::env::temp_dir().join(format!("funoform_controller_test_{}", std::process::id()));
+            std::env::set_var("XDG_STATE_HOME", &dir);
+            std::env::set_var("XDG_CACHE_HOME", &dir);
+            dir
+        });
+    }
+
+    fn songs(numbers: Range<usize>) -> Vec<PathBuf> {
+        numbers.map(|number| PathBuf::from(format!("/music/{:02}.mp3", number))).collect()
+    }
+
+    /// Makes a controller in random mode with the specified number of songs queued and nothing
+    /// playing
+    fn queued_controller(count: usize) -> SongControlThread {
+        use_scratch_dirs();
+        let (notifier, _) = mpsc::channel();
+        let (dir_event_sender, _) = mpsc::channel();
+        let initial = InitialSettings {
+            random: Some(true),
+            resume: ResumeMode::Off,
+            ..InitialSettings::default()
+        };
+        let mut sct = SongControlThread::init(String::new(), initial, None, MusicPlayer::init(notifier), dir_event_sender);
+        sct.insert_into_queue(0, songs(0..count), false);
+        sct
+    }
+
+    /// Gets the songs that have played this cycle in random mode, and the songs still to play, in
+    /// order. Also checks the shuffle order still covers every song in the queue exactly once.
+    fn shuffle_state(sct: &SongControlThread) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let mut order = sct._shuffle_order.clone();
+        order.sort();
+        assert_eq!(order, (0..sct._queued_music_files.len()).collect::<Vec<usize>>());
+        let song = |&index: &usize| sct._queued_music_files[index].clone();
+        let played = sct._shuffle_order[..sct._shuffle_pos].iter().map(song).collect();
+        let upcoming = sct._shuffle_order[sct._shuffle_pos..].iter().map(song).collect();
+        (played, upcoming)
+    }
+
+    #[test]
+    fn seeking_by_huge_offsets_stays_within_the_song() {
+        let mut sct = queued_controller(1);
+        sct._cur_settings.song_playing = "/music/00.mp3".to_string();
+        sct._cur_settings.song_time = (10, Some(60));
+        sct.seek_by(1e300).unwrap();
+        assert_eq!(sct._cur_settings.song_time, (60, Some(60)));
+        sct.seek_by(-1e300).unwrap();
+        assert_eq!(sct._cur_settings.song_time, (0, Some(60)));
+
+        // Without a known length, the furthest we can go is as far as a position can count
+        sct._cur_settings.song_time = (10, None);
+        sct.seek_by(1e300).unwrap();
+        assert_eq!(sct._cur_settings.song_time, (u32::MAX, None));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn seeking_with_nothing_playing_fails() {
+        let mut sct = queued_controller(1);
+        assert!(matches!(sct.seek(Duration::from_secs(5)), Err(PlayerError::NothingPlaying)));
+        assert!(matches!(sct.seek_by(5.0), Err(PlayerError::NothingPlaying)));
+        assert_eq!(sct._cur_settings.song_time, (0, None));
+    }
+
+    #[test]
+    fn session_changes_are_saved_together() {
+        let mut sct = queued_controller(3);
+        sct.save_session();
+        for _ in 0..10 {
+            sct.set_volume(0.25);
+        }
+        let changed_at = sct._session_changed_at.expect("the volume is part of the session");
+        sct.save_session_if_due();
+        assert_eq!(sct._session_changed_at, Some(changed_at));
+
+        sct._session_changed_at = Some(changed_at - SESSION_CHANGE_SAVE_DELAY);
+        sct.save_session_if_due();
+        assert!(sct._session_changed_at.is_none());
+        assert_eq!(saved_state::load_session().map(|session| session.volume), Some(0.25));
+    }
+
+    #[test]
+    fn going_back_in_random_mode_replays_the_song_left_next() {
+        let mut sct = queued_controller(5);
+        sct.reshuffle();
+        let order = sct._shuffle_order.clone();
+        // The first two songs in the shuffle order have played, and the second is playing now
+        sct._shuffle_pos = 2;
+        sct._cur_playing_index = order[1] as i64;
+        sct._cur_song_started = true;
+        sct._play_history = vec![order[0]];
+
+        // The songs don't exist, so can't actually be played, but still become the current song
+        let _ = sct.prior_track();
+        assert_eq!(sct.get_queue_index(), Some(order[0]));
+        assert!(sct._play_history.is_empty());
+        let _ = sct.advance_and_play(false);
+        assert_eq!(sct.get_queue_index(), Some(order[1]));
+        let _ = sct.advance_and_play(false);
+        assert_eq!(sct.get_queue_index(), Some(order[2]));
+    }
+
+    #[test]
+    fn unplayable_songs_arent_remembered_as_played() {
+        let mut sct = queued_controller(3);
+        sct._cur_settings.random = false;
+        // None of the songs exist, so every one is tried and skipped
+        assert!(sct.play_next_song(false).is_err());
+        assert_eq!(sct.get_queue_index(), Some(2));
+        let _ = sct.play_queued(0);
+        assert!(sct._play_history.is_empty());
+    }
+
+    #[test]
+    fn browsing_needs_music_in_or_under_the_dir() {
+        let mut sct = queued_controller(0);
+        let dir = std::env::temp_dir().join(format!("funoform_browse_test_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+        std::fs::create_dir_all(dir.join("album")).unwrap();
+        std::fs::write(dir.join("album/01.mp3"), b"").unwrap();
+
+        let empty = dir.join("empty").to_string_lossy().to_string();
+        let error = sct.set_browsing_dir(&empty).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert_ne!(sct.get_browsing_dir(), empty);
+
+        // Music in a subdirectory is still something to browse to
+        let top = dir.to_string_lossy().to_string();
+        sct.set_browsing_dir(&top).unwrap();
+        assert_eq!(sct.get_browsing_dir(), top);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reshuffle_never_starts_with_current_song() {
+        let mut sct = queued_controller(3);
+        sct._cur_playing_index = 1;
+        for _ in 0..50 {
+            sct.reshuffle();
+            assert_ne!(sct._shuffle_order[0], 1);
+            shuffle_state(&sct);
+        }
+    }
+
+    #[test]
+    fn shuffle_follows_removed_songs() {
+        let mut sct = queued_controller(10);
+        sct.reshuffle();
+        sct._shuffle_pos = 4;
+        let (mut played, mut upcoming) = shuffle_state(&sct);
+
+        // One song that has played this cycle and one that hasn't
+        for song in [played[1].clone(), upcoming[2].clone()] {
+            let index = sct._queued_music_files.iter().position(|queued| *queued == song).unwrap();
+            sct.remove_from_queue(index);
+            played.retain(|played| *played != song);
+            upcoming.retain(|upcoming| *upcoming != song);
+        }
+        assert_eq!(shuffle_state(&sct), (played, upcoming));
+        assert_eq!(sct._shuffle_pos, 3);
+    }
+
+    #[test]
+    fn shuffle_follows_moved_songs() {
+        let mut sct = queued_controller(10);
+        sct.reshuffle();
+        sct._shuffle_pos = 5;
+        let before = shuffle_state(&sct);
+        sct.move_queued(0, 9).unwrap();
+        sct.move_queued(7, 2).unwrap();
+        assert_eq!(shuffle_state(&sct), before);
+    }
+
+    #[test]
+    fn inserted_songs_are_still_to_play() {
+        let mut sct = queued_controller(10);
+        sct.reshuffle();
+        sct._shuffle_pos = 5;
+        let (played, upcoming) = shuffle_state(&sct);
+
+        sct.insert_into_queue(3, songs(20..22), true);
+        let (now_played, now_upcoming) = shuffle_state(&sct);
+        assert_eq!(now_played, played);
+        assert_eq!(now_upcoming[..2], songs(20..22));
+        assert_eq!(now_upcoming[2..], upcoming);
+
+        sct.insert_into_queue(12, songs(30..33), false);
+        let (now_played, mut now_upcoming) = shuffle_state(&sct);
+        assert_eq!(now_played, played);
+        now_upcoming.retain(|song| !songs(30..33).contains(song));
+        assert_eq!(now_upcoming[..2], songs(20..22));
+        assert_eq!(now_upcoming[2..], upcoming);
+    }
+
+    /// Gets the current song and the play history as songs rather than positions
+    fn current_and_history(sct: &SongControlThread) -> (Option<PathBuf>, Vec<PathBuf>) {
+        let current = sct.get_queue_index().map(|index| sct._queued_music_files[index].clone());
+        let history = sct._play_history.iter().map(|&index| sct._queued_music_files[index].clone()).collect();
+        (current, history)
+    }
+
+    /// Puts the controller part way through the queue, as if songs 1 and 4 had played and 6 was
+    /// playing now
+    fn part_way_through(sct: &mut SongControlThread) {
+        sct._play_history = vec![1, 4];
+        sct._cur_playing_index = 6;
+    }
+
+    #[test]
+    fn current_song_and_history_follow_moves() {
+        let mut sct = queued_controller(10);
+        part_way_through(&mut sct);
+        let ids = sct.get_queue_ids();
+        sct.move_queued(6, 0).unwrap();
+        sct.move_queued(2, 8).unwrap();
+        assert_eq!(current_and_history(&sct), (Some(songs(6..7)[0].clone()), vec![songs(1..2)[0].clone(), songs(4..5)[0].clone()]));
+        assert_eq!(sct.queue_index_of(ids[6]), Some(0));
+        assert_eq!(sct._queued_music_files[sct.queue_index_of(ids[2]).unwrap()], songs(2..3)[0]);
+    }
+
+    #[test]
+    fn current_song_and_history_follow_inserts() {
+        let mut sct = queued_controller(10);
+        part_way_through(&mut sct);
+        let ids = sct.get_queue_ids();
+        sct.insert_into_queue(0, songs(20..22), false);
+        sct.insert_into_queue(5, songs(30..31), false);
+        sct.insert_into_queue(13, songs(40..42), false);
+        assert_eq!(current_and_history(&sct), (Some(songs(6..7)[0].clone()), vec![songs(1..2)[0].clone(), songs(4..5)[0].clone()]));
+        assert_eq!(sct._queued_music_files.len(), 15);
+        // Songs keep their ids, and new songs get new ones
+        assert_eq!(sct.queue_index_of(ids[6]), sct.get_queue_index());
+        let mut all_ids = sct.get_queue_ids();
+        all_ids.sort();
+        all_ids.dedup();
+        assert_eq!(all_ids.len(), 15);
+    }
+
+    #[test]
+    fn removing_songs_keeps_current_song_and_history() {
+        let mut sct = queued_controller(10);
+        part_way_through(&mut sct);
+        sct.remove_queued(4).unwrap();
+        sct.remove_queued(0).unwrap();
+        assert_eq!(current_and_history(&sct), (Some(songs(6..7)[0].clone()), vec![songs(1..2)[0].clone()]));
+        assert!(sct.remove_queued(8).is_err());
+    }
+
+    #[test]
+    fn removing_current_song_makes_the_next_one_next() {
+        let mut sct = queued_controller(10);
+        sct._cur_settings.random = false;
+        part_way_through(&mut sct);
+        sct.remove_queued(6).unwrap();
+        // The removed song plays on, but no longer has a place in the queue
+        assert_eq!(sct.get_queue_index(), None);
+        assert_eq!(sct.session().cur_index, None);
+        assert_eq!(current_and_history(&sct), (None, vec![songs(1..2)[0].clone(), songs(4..5)[0].clone()]));
+
+        // The song that took its place plays next. It can't actually be played, as it doesn't
+        // exist, but it still becomes the current song.
+        let _ = sct.advance_and_play(false);
+        assert_eq!(current_and_history(&sct).0, Some(songs(7..8)[0].clone()));
+        // The removed song isn't remembered as played
+        assert_eq!(sct._play_history.len(), 2);
+    }
+
+    #[test]
+    fn removing_songs_around_a_removed_current_song() {
+        let mut sct = queued_controller(10);
+        sct._cur_settings.random = false;
+        part_way_through(&mut sct);
+        sct.remove_queued(6).unwrap();
+        // The song before where it was, then a song before that, then the song that took its place
+        sct.remove_queued(5).unwrap();
+        sct.remove_queued(0).unwrap();
+        sct.remove_queued(4).unwrap();
+        assert_eq!(sct.get_queue_index(), None);
+        let _ = sct.advance_and_play(false);
+        assert_eq!(current_and_history(&sct).0, Some(songs(8..9)[0].clone()));
+    }
+
+    #[test]
+    fn going_back_from_a_removed_current_song() {
+        let mut sct = queued_controller(10);
+        sct._cur_settings.random = false;
+        part_way_through(&mut sct);
+        sct.remove_queued(6).unwrap();
+        let _ = sct.prior_track();
+        assert_eq!(current_and_history(&sct).0, Some(songs(5..6)[0].clone()));
+    }
+
+    #[test]
+    fn queue_changes_are_tracked_by_version() {
+        let mut sct = queued_controller(5);
+        let version = sct._cur_settings.queue_version;
+        sct.move_queued(3, 1).unwrap();
+        // Both songs between the two positions shifted along with the one moved
+        assert_eq!(sct.queue_changes_since(version), vec![1, 2, 3]);
+        let version = sct._cur_settings.queue_version;
+        sct.remove_queued(4).unwrap();
+        assert!(sct.queue_changes_since(version).is_empty());
+    }
+
+    #[test]
+    fn turning_random_on_starts_a_new_cycle() {
+        let mut sct = queued_controller(5);
+        sct.reshuffle();
+        sct._shuffle_pos = 3;
+        sct.set_random(false);
+        sct.set_random(true);
+        assert!(sct._shuffle_order.is_empty());
+    }
+}
@@ -0,0 +1,219 @@
+use std::sync::{Arc, Mutex, Weak};
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+
+/// What the bus needs to know about an event to decide what to drop when a subscriber falls behind
+pub trait BusEvent: Clone {
+    /// Returns true if the event is soon made stale by the next one like it, like a progress
+    /// update, so it can be dropped without the subscriber losing track of anything
+    fn is_transient(&self) -> bool;
+    /// Makes the event that tells a subscriber it missed events and must fetch the full state again
+    fn resync() -> Self;
+}
+
+/// One subscriber's queue. The bus keeps a receiver for the queue too, so it can throw away the
+/// oldest event when the subscriber falls behind.
+struct Subscriber<T> {
+    id: u64,
+    sender: Sender<T>,
+    receiver: Receiver<T>,
+}
+
+struct Subscribers<T> {
+    next_id: u64,
+    list: Vec<Subscriber<T>>,
+}
+
+/// Delivers every event published to every subscriber. Each subscriber has its own queue of a
+/// fixed size. When a subscriber falls behind and its queue fills up, the transient events in it
+/// are dropped to make room. If that isn't enough, everything queued is replaced by a resync
+/// event, so the subscriber knows to catch up on the full state rather than carry on out of step.
+pub struct EventBus<T: BusEvent> {
+    subscribers: Arc<Mutex<Subscribers<T>>>,
+}
+
+/// A subscriber's end of the bus. Dropping it unsubscribes.
+pub struct Subscription<T> {
+    id: u64,
+    receiver: Receiver<T>,
+    subscribers: Weak<Mutex<Subscribers<T>>>,
+}
+
+impl<T: BusEvent> EventBus<T> {
+    pub fn new() -> EventBus<T> {
+        EventBus {
+            subscribers: Arc::new(Mutex::new(Subscribers {
+                next_id: 0,
+                list: Vec::new(),
+            })),
+        }
+    }
+
+    /// Adds a subscriber that receives every event published from now on
+    /// # Parameters
+    /// capacity = How many events the subscriber can fall behind by before events are dropped.
+    ///            At least 2, so there is always room for a resync and the event after it.
+    pub fn subscribe(&self, capacity: usize) -> Subscription<T> {
+        let (sender, receiver) = bounded(capacity.max(2));
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let id = subscribers.next_id;
+        subscribers.next_id += 1;
+        subscribers.list.push(Subscriber {
+            id,
+            sender,
+            receiver: receiver.clone(),
+        });
+        Subscription {
+            id,
+            receiver,
+            subscribers: Arc::downgrade(&self.subscribers),
+        }
+    }
+
+    /// Sends an event to every subscriber
+    pub fn publish(&self, event: T) {
+        let subscribers = self.subscribers.lock().unwrap();
+        for subscriber in &subscribers.list {
+            if let Err(TrySendError::Full(event)) = subscriber.sender.try_send(event.clone()) {
+                subscriber.send_when_full(event);
+            }
+        }
+    }
+}
+
+impl<T: BusEvent> Subscriber<T> {
+    /// Queues an event when the queue is full, by dropping transient events to make room, or
+    /// failing that, everything but a resync. Only the subscriber could be taking events out at
+    /// the same time, and it only takes them from the front, so the events kept stay in order.
+    fn send_when_full(&self, event: T) {
+        let mut queued: Vec<T> = self.receiver.try_iter().filter(|queued| !queued.is_transient()).collect();
+        if queued.len() >= self.receiver.capacity().unwrap_or(usize::MAX) {
+            if !event.is_transient() {
+                queued = vec![T::resync(), event];
+            }
+        } else {
+            queued.push(event);
+        }
+        for event in queued {
+            let _ = self.sender.try_send(event);
+        }
+    }
+}
+
+impl<T> Subscription<T> {
+    /// Gets the receiving end of the subscriber's queue
+    pub fn receiver(&self) -> &Receiver<T> {
+        &self.receiver
+    }
+}
+
+impl<T> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        if let Some(subscribers) = self.subscribers.upgrade() {
+            subscribers.lock().unwrap().list.retain(|subscriber| subscriber.id != self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum TestEvent {
+        Progress(u32),
+        Change(u32),
+        Resync,
+    }
+
+    impl BusEvent for TestEvent {
+        fn is_transient(&self) -> bool {
+            matches!(self, TestEvent::Progress(..))
+        }
+
+        fn resync() -> Self {
+            TestEvent::Resync
+        }
+    }
+
+    fn subscriber_count(bus: &EventBus<TestEvent>) -> usize {
+        bus.subscribers.lock().unwrap().list.len()
+    }
+
+    fn received(subscription: &Subscription<TestEvent>) -> Vec<TestEvent> {
+        subscription.receiver().try_iter().collect()
+    }
+
+    #[test]
+    fn every_subscriber_gets_every_event() {
+        let bus = EventBus::new();
+        let first = bus.subscribe(8);
+        bus.publish(TestEvent::Change(1));
+        let second = bus.subscribe(8);
+        bus.publish(TestEvent::Progress(2));
+        assert_eq!(received(&first), [TestEvent::Change(1), TestEvent::Progress(2)]);
+        assert_eq!(received(&second), [TestEvent::Progress(2)]);
+    }
+
+    #[test]
+    fn dropping_a_subscription_unsubscribes() {
+        let bus = EventBus::new();
+        let kept = bus.subscribe(2);
+        let dropped = bus.subscribe(2);
+        assert_eq!(subscriber_count(&bus), 2);
+        drop(dropped);
+        assert_eq!(subscriber_count(&bus), 1);
+        // The bus no longer fills a queue nobody reads
+        bus.publish(TestEvent::Change(1));
+        assert_eq!(received(&kept), [TestEvent::Change(1)]);
+
+        // Outliving the bus is fine too
+        drop(bus);
+        drop(kept);
+    }
+
+    #[test]
+    fn full_queue_drops_transient_events_first() {
+        let bus = EventBus::new();
+        let subscription = bus.subscribe(3);
+        bus.publish(TestEvent::Progress(1));
+        bus.publish(TestEvent::Change(2));
+        bus.publish(TestEvent::Progress(3));
+        bus.publish(TestEvent::Change(4));
+        assert_eq!(received(&subscription), [TestEvent::Change(2), TestEvent::Change(4)]);
+    }
+
+    #[test]
+    fn transient_event_is_dropped_when_no_room() {
+        let bus = EventBus::new();
+        let subscription = bus.subscribe(2);
+        bus.publish(TestEvent::Change(1));
+        bus.publish(TestEvent::Change(2));
+        bus.publish(TestEvent::Progress(3));
+        assert_eq!(received(&subscription), [TestEvent::Change(1), TestEvent::Change(2)]);
+    }
+
+    #[test]
+    fn full_queue_of_changes_becomes_resync() {
+        let bus = EventBus::new();
+        let subscription = bus.subscribe(3);
+        for n in 1..=4 {
+            bus.publish(TestEvent::Change(n));
+        }
+        assert_eq!(received(&subscription), [TestEvent::Resync, TestEvent::Change(4)]);
+        // Once caught up, events flow normally again
+        bus.publish(TestEvent::Change(5));
+        assert_eq!(received(&subscription), [TestEvent::Change(5)]);
+    }
+
+    #[test]
+    fn slow_subscriber_doesnt_affect_others() {
+        let bus = EventBus::new();
+        let slow = bus.subscribe(2);
+        let fast = bus.subscribe(2);
+        for n in 1..=5 {
+            bus.publish(TestEvent::Change(n));
+            assert_eq!(received(&fast), [TestEvent::Change(n)]);
+        }
+        assert_eq!(received(&slow), [TestEvent::Resync, TestEvent::Change(5)]);
+    }
+}
@@ -1,10 +1,60 @@
 /// Utilities for reading directories and music files from disk
+#[allow(clippy::module_inception)]
 pub mod file_utils {
-    use std::ffi::OsStr;
+    use std::collections::VecDeque;
+    use std::ffi::{OsStr, OsString};
     use std::fs;
-    use std::fs::DirEntry;
     use std::io;
-    use std::path::PathBuf;
+    use std::os::unix::ffi::OsStringExt;
+    use std::path::{Path, PathBuf};
+    use std::sync::OnceLock;
+    use glob::Pattern;
+
+    /// The music file extensions used when the config file doesn't list any. These are the formats
+    /// rodio can play back.
+    pub const DEFAULT_EXTENSIONS: [&str; 4] = ["mp3", "flac", "wav", "ogg"];
+
+    /// Decides which files count as music files and which files and directories are skipped
+    struct FileFilter {
+        // Lower case, without the leading dot
+        extensions: Vec<String>,
+        exclude: Vec<Pattern>,
+    }
+
+    static FILE_FILTER: OnceLock<FileFilter> = OnceLock::new();
+
+    /// Sets which extensions count as music files, and the glob patterns of files and directories
+    /// to skip. Must be called before any directories are read, later calls are ignored.
+    pub fn set_file_filter(extensions: Vec<String>, exclude: Vec<Pattern>) {
+        let extensions = extensions.iter().map(|ext| ext.to_ascii_lowercase()).collect();
+        if FILE_FILTER.set(FileFilter { extensions, exclude }).is_err() {
+            eprintln!("Ignoring attempt to change the music file filter once in use");
+        }
+    }
+
+    fn file_filter() -> &'static FileFilter {
+        FILE_FILTER.get_or_init(|| FileFilter {
+            extensions: DEFAULT_EXTENSIONS.iter().map(|ext| ext.to_string()).collect(),
+            exclude: Vec::new(),
+        })
+    }
+
+    /// Describes the file filter in use, so anything that remembers which files are music files can
+    /// tell when the filter has changed
+    pub fn file_filter_description() -> String {
+        let filter = file_filter();
+        let exclude: Vec<&str> = filter.exclude.iter().map(Pattern::as_str).collect();
+        format!("extensions={} exclude={}", filter.extensions.join(","), exclude.join(","))
+    }
+
+    /// Returns true if the specified file or directory matches one of the exclude patterns. A
+    /// pattern can match either the whole path or just the file name.
+    pub fn is_excluded(path: &Path) -> bool {
+        let file_name = path.file_name().map(Path::new);
+        file_filter().exclude.iter().any(|pattern| {
+            pattern.matches_path(path) || file_name.is_some_and(|name| pattern.matches_path(name))
+        })
+    }
 
     /// Gets a collection of all subdirectories in the specified starting directory.
     pub fn sub_directories(starting_dir: &str) -> io::Result<Vec<String>> {
@@ -14,7 +64,7 @@ pub mod file_utils {
         for dir_entry_res in fs::read_dir(dir)? {
             match dir_entry_res {
                 Ok(maybe_dir) => {
-                    if maybe_dir.metadata()?.is_dir() {
+                    if maybe_dir.metadata()?.is_dir() && !is_excluded(&maybe_dir.path()) {
                         match maybe_dir.file_name().to_str() {
                             Some(final_dir_name) => subdirs.push(final_dir_name.to_string()),
                             None => continue,
@@ -27,67 +77,279 @@ pub mod file_utils {
         Ok(subdirs)
     }
 
-    /// Allows getting a list of all the music files in the specified directory
-    /// # Parameters
-    /// dir_to_scan = The absolute or relative path to the directory you want to query.
-    /// recursive = If false, this method only returns music files found in the specified directory.
-    ///             If true, this method also includes files found in subdirectories.
-    pub fn list_music_files(dir_to_scan: &str, recursive: bool) -> io::Result<Vec<PathBuf>> {
-        let directory = PathBuf::from(&dir_to_scan);
-        _list_music_files(&directory, recursive)
-    }
-
-    /// Annoyingly, rust doesn't allow method overrides. So we keep our public method with the nice
-    /// name and use this underscore version for our private version.
-    fn _list_music_files(dir_to_scan: &PathBuf, recursive: bool) -> io::Result<Vec<PathBuf>> {
-        let mut mp3_files = Vec::new();
-        let mut had_recursive_dirs: bool = false;
-
-        // Read all files in the directory
-        if dir_to_scan.is_dir() {
-            for entry in fs::read_dir(&dir_to_scan)? {
-                let entry: DirEntry = entry?;
-                let path: PathBuf = entry.path();
-
-                if recursive && entry.file_type()?.is_dir() {
-                    // if we were asked to recursively list files and our directory contains a
-                    // subdirectory then get all the files from the subdirectory
-                    mp3_files.append(&mut _list_music_files(&path, true)?);
-                    // Just for logging purposes, note that the file count from this directory
-                    // included recursive directories
-                    had_recursive_dirs = true;
-                } else {
-                    // This is just a file. Check if it is a supported music file
-                    if is_supported_audio_file(&path) {
-                        mp3_files.push(path);
-                    }
+    /// Returns true if there is a music file or playlist in the specified directory or anywhere
+    /// under it. Nearer directories are searched first, and the search stops at the first one found.
+    /// Subdirectories that can't be read are skipped.
+    pub fn contains_music(dir: &Path) -> io::Result<bool> {
+        let mut dirs_to_search = VecDeque::from([dir.to_path_buf()]);
+        while let Some(search_dir) = dirs_to_search.pop_front() {
+            let entries = match fs::read_dir(&search_dir) {
+                Ok(entries) => entries,
+                Err(_) if search_dir != dir => continue,
+                Err(e) => return Err(e),
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if is_excluded(&path) {
+                    continue;
+                }
+                // Follows symlinks, as the library does
+                if path.is_dir() {
+                    dirs_to_search.push_back(path);
+                } else if is_supported_audio_file(&path) || is_playlist_file(&path) {
+                    return Ok(true);
                 }
             }
         }
+        Ok(false)
+    }
 
-        // print out the count of music files in this directory, and an indicator if some of that
-        // count came from subdirectories
-        let mut recursively: &str = "";
-        if had_recursive_dirs {
-            recursively = "recursively";
+    /// Gets the names of all the playlist files in the specified directory
+    pub fn playlist_files(dir: &str) -> io::Result<Vec<String>> {
+        let mut playlists = Vec::new();
+        for entry in fs::read_dir(dir)?.flatten() {
+            if entry.file_type()?.is_file() && is_playlist_file(&entry.path()) {
+                if let Some(name) = entry.file_name().to_str() {
+                    playlists.push(name.to_string());
+                }
+            }
         }
-        println!("Found {} music files in {} {}", mp3_files.len(), dir_to_scan.display(), recursively);
+        Ok(playlists)
+    }
 
-        Ok(mp3_files)
+    /// Returns true if the specified file is a playlist we know how to read (m3u, m3u8, or pls)
+    pub fn is_playlist_file(file_path: &Path) -> bool {
+        match file_path.extension().and_then(OsStr::to_str) {
+            Some(ext) => ext.eq_ignore_ascii_case("m3u8") || ext.eq_ignore_ascii_case("m3u") ||
+                ext.eq_ignore_ascii_case("pls"),
+            None => false,
+        }
+    }
+
+    /// Reads the music files listed in an M3U, M3U8, or PLS playlist. Relative paths are resolved
+    /// against the directory the playlist is in. Entries that are URLs, aren't supported music
+    /// files, or don't exist are skipped.
+    pub fn read_playlist(playlist_path: &Path) -> io::Result<Vec<PathBuf>> {
+        let bytes = fs::read(playlist_path)?;
+        // M3U8 and PLS are UTF-8. Plain M3U is traditionally Latin-1, where every byte is a char.
+        let contents = match String::from_utf8(bytes) {
+            Ok(contents) => contents,
+            Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+        };
+
+        let is_pls = playlist_path.extension().and_then(OsStr::to_str)
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("pls"));
+        let entries = if is_pls {
+            pls_entries(&contents)
+        } else {
+            m3u_entries(&contents)
+        };
+
+        let playlist_dir = playlist_path.parent().unwrap_or(Path::new(""));
+        let mut music_files = Vec::new();
+        for entry in entries {
+            let Some(path) = entry_to_path(&entry, playlist_dir) else {
+                eprintln!("Skipping playlist entry that isn't a local file: {}", entry);
+                continue;
+            };
+            if !is_supported_audio_file(&path) || !path.is_file() {
+                eprintln!("Skipping missing or unsupported playlist entry: {}", path.display());
+                continue;
+            }
+            music_files.push(path);
+        }
+        Ok(music_files)
+    }
+
+    /// Writes the specified songs out as an M3U8 playlist. Each song gets an #EXTINF line from the
+    /// (duration in seconds, display name) pair given for it. Songs in or under the playlist's
+    /// directory are written as relative paths, so the playlist keeps working if the whole
+    /// directory is moved or shared.
+    pub fn write_m3u8(playlist_path: &Path, songs: &[(PathBuf, Option<u32>, String)]) -> io::Result<()> {
+        let playlist_dir = playlist_path.parent().unwrap_or(Path::new(""));
+        let mut contents = String::from("#EXTM3U\n");
+        for (song, duration, display_name) in songs {
+            // -1 is the M3U convention for an unknown duration
+            let duration: i64 = duration.map_or(-1, i64::from);
+            let path = song.strip_prefix(playlist_dir).unwrap_or(song);
+            contents.push_str(&format!("#EXTINF:{},{}\n{}\n", duration, display_name, path.display()));
+        }
+        fs::write(playlist_path, contents)
+    }
+
+    /// Gets the entries of an M3U playlist. Every line that isn't blank or a # directive (like
+    /// #EXTM3U or #EXTINF) is an entry.
+    fn m3u_entries(contents: &str) -> Vec<String> {
+        contents.lines()
+            .map(|line| line.trim().trim_start_matches('\u{feff}'))
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Gets the entries of a PLS playlist, which are the FileN=... keys, in N order
+    fn pls_entries(contents: &str) -> Vec<String> {
+        let mut entries: Vec<(u32, String)> = contents.lines()
+            .filter_map(|line| {
+                let (key, value) = line.trim().split_once('=')?;
+                let key = key.trim();
+                if !key.get(..4)?.eq_ignore_ascii_case("file") {
+                    return None;
+                }
+                let number: u32 = key[4..].parse().ok()?;
+                Some((number, value.trim().to_string()))
+            })
+            .collect();
+        entries.sort_by_key(|(number, _)| *number);
+        entries.into_iter().map(|(_, entry)| entry).collect()
+    }
+
+    /// Turns a playlist entry into a local path. Returns None for URLs other than file:// ones.
+    fn entry_to_path(entry: &str, playlist_dir: &Path) -> Option<PathBuf> {
+        let path = match entry.strip_prefix("file://") {
+            // URLs escape spaces and anything else unusual, and may name the local host
+            Some(path) => {
+                let path = if path.starts_with("localhost/") { &path["localhost".len()..] } else { path };
+                PathBuf::from(OsString::from_vec(percent_decode_bytes(path)))
+            }
+            None if entry.contains("://") => return None,
+            // Playlists made on Windows use backslashes
+            None if !entry.contains('/') => PathBuf::from(entry.replace('\\', "/")),
+            None => PathBuf::from(entry),
+        };
+        if path.is_absolute() {
+            Some(path)
+        } else {
+            Some(playlist_dir.join(path))
+        }
+    }
+
+    /// Decodes the %XX escapes in a URL or part of one. Anything that isn't a valid escape is left
+    /// as it is.
+    pub fn percent_decode(text: &str) -> String {
+        String::from_utf8_lossy(&percent_decode_bytes(text)).into_owned()
+    }
+
+    /// Decodes the %XX escapes like percent_decode, but leaves the result as bytes, as they needn't
+    /// be UTF-8
+    fn percent_decode_bytes(text: &str) -> Vec<u8> {
+        let bytes = text.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            let escaped = bytes.get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            match escaped {
+                Some(byte) if bytes[i] == b'%' => {
+                    decoded.push(byte);
+                    i += 3;
+                }
+                _ => {
+                    decoded.push(bytes[i]);
+                    i += 1;
+                }
+            }
+        }
+        decoded
     }
 
     /// Returns true if the specified file is a music file this app can play back, false otherwise.
-    /// Note, supported file types are those supported by rodio. Currently, that means mp3, wav,
-    /// flac, and vorbis (ogg).
-    fn is_supported_audio_file(file_path: &PathBuf) -> bool {
+    /// Note, supported file types are those listed in the config file, which default to the ones
+    /// supported by rodio: mp3, wav, flac, and vorbis (ogg). Excluded files are never supported.
+    pub fn is_supported_audio_file(file_path: &Path) -> bool {
         match file_path.extension().and_then(OsStr::to_str) {
             Some(ext) => {
-                // note, start with the most common file extensions so we don't waste cycles checking for
-                // less common extensions
-                return ext.eq_ignore_ascii_case("mp3") || ext.eq_ignore_ascii_case("flac") ||
-                    ext.eq_ignore_ascii_case("wav") || ext.eq_ignore_ascii_case("ogg");
+                file_filter().extensions.iter().any(|supported| ext.eq_ignore_ascii_case(supported)) &&
+                    !is_excluded(file_path)
             }
             None => false,
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// Makes a fresh scratch directory for a test, holding the named (empty) music files
+        fn scratch_dir(test_name: &str, music_files: &[&str]) -> PathBuf {
+            let dir = std::env::temp_dir().join(format!("funoform_playlist_test_{}_{}", std::process::id(), test_name));
+            let _ = fs::remove_dir_all(&dir);
+            for file in music_files {
+                let path = dir.join(file);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, b"").unwrap();
+            }
+            dir
+        }
+
+        #[test]
+        fn m3u_resolves_relative_and_absolute_entries() {
+            let dir = scratch_dir("m3u", &["a.mp3", "sub/b.flac", "c.mp3"]);
+            let contents = format!("\u{feff}#EXTM3U\n#EXTINF:12,A\na.mp3\n\n  sub/b.flac  \n{}\nmissing.mp3\nnotes.txt\n",
+                dir.join("c.mp3").display());
+            fs::write(dir.join("list.m3u8"), contents).unwrap();
+            assert_eq!(read_playlist(&dir.join("list.m3u8")).unwrap(),
+                vec![dir.join("a.mp3"), dir.join("sub/b.flac"), dir.join("c.mp3")]);
+        }
+
+        #[test]
+        fn m3u_accepts_windows_paths() {
+            let dir = scratch_dir("windows", &["sub/deeper/a.mp3"]);
+            fs::write(dir.join("list.m3u"), "sub\\deeper\\a.mp3\r\n").unwrap();
+            assert_eq!(read_playlist(&dir.join("list.m3u")).unwrap(), vec![dir.join("sub/deeper/a.mp3")]);
+        }
+
+        #[test]
+        fn m3u_reads_latin1() {
+            let dir = scratch_dir("latin1", &["café.mp3"]);
+            fs::write(dir.join("list.m3u"), b"#EXTINF:-1,Caf\xe9\ncaf\xe9.mp3\n").unwrap();
+            assert_eq!(read_playlist(&dir.join("list.m3u")).unwrap(), vec![dir.join("café.mp3")]);
+        }
+
+        #[test]
+        fn pls_entries_are_in_number_order() {
+            let dir = scratch_dir("pls", &["a.mp3", "b.mp3", "c.ogg"]);
+            let contents = "[playlist]\nFile2=b.mp3\nTitle2=B\nfile10=c.ogg\nFile1 = a.mp3\nFileX=nope.mp3\nNumberOfEntries=3\n";
+            fs::write(dir.join("list.pls"), contents).unwrap();
+            assert_eq!(read_playlist(&dir.join("list.pls")).unwrap(),
+                vec![dir.join("a.mp3"), dir.join("b.mp3"), dir.join("c.ogg")]);
+        }
+
+        #[test]
+        fn file_urls_are_decoded() {
+            let dir = scratch_dir("file_url", &["my song.mp3", "100%.mp3"]);
+            let url = format!("file://{}/my%20song.mp3", dir.display());
+            let localhost_url = format!("file://localhost{}/100%25.mp3", dir.display());
+            fs::write(dir.join("list.m3u8"), format!("{}\n{}\nhttp://example.com/stream.mp3\n", url, localhost_url)).unwrap();
+            assert_eq!(read_playlist(&dir.join("list.m3u8")).unwrap(),
+                vec![dir.join("my song.mp3"), dir.join("100%.mp3")]);
+        }
+
+        #[test]
+        fn only_local_urls_become_paths() {
+            let dir = Path::new("/lists");
+            assert_eq!(entry_to_path("https://example.com/a.mp3", dir), None);
+            assert_eq!(entry_to_path("file:///music/a%C3%A9.mp3", dir), Some(PathBuf::from("/music/aé.mp3")));
+            // Non-UTF-8 escapes still name the file they encode
+            assert_eq!(entry_to_path("file:///music/%E9.mp3", dir),
+                Some(PathBuf::from(OsString::from_vec(b"/music/\xe9.mp3".to_vec()))));
+        }
+
+        #[test]
+        fn music_is_found_at_any_depth() {
+            let dir = scratch_dir("contains_music", &["empty/notes.txt", "deep/er/still/a.flac", "lists/mix.m3u"]);
+            assert!(contains_music(&dir).unwrap());
+            assert!(contains_music(&dir.join("deep")).unwrap());
+            assert!(contains_music(&dir.join("lists")).unwrap());
+            assert!(!contains_music(&dir.join("empty")).unwrap());
+            assert!(contains_music(&dir.join("missing")).is_err());
+        }
+
+        #[test]
+        fn invalid_escapes_are_left_alone() {
+            assert_eq!(percent_decode("a%20b%2"), "a b%2");
+            assert_eq!(percent_decode("%zz%41"), "%zzA");
+        }
+    }
 }
\ No newline at end of file
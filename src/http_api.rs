@@ -0,0 +1,467 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+use crate::controller::Controller;
+use crate::file_utils::file_utils;
+use crate::music_player::PlayerError;
+use crate::player_event::PlayerEvent;
+
+/// How often an idle event stream pings the client, so connections through proxies stay open
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long an event stream waits on the client before checking for events to send. Also the
+/// longest an event waits before it is sent.
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a client has to send its request, or take our answer, before we give up on it
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The most we accept in a request. Our requests are small, so anything more is a mistake.
+const MAX_HEAD_LEN: usize = 16 * 1024;
+const MAX_HEADERS: usize = 64;
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+/// Lets kiosks and scripts control the player with JSON over HTTP. Every endpoint answers with
+/// JSON, or 204 No Content for commands that succeed.
+///
+/// GET /status, the full current state
+/// GET /dirs, the directories and playlists in the browsing directory
+/// POST /play, /pause, /stop, /next, /previous
+/// POST /seek with {"position": secs} to seek to a spot, or {"offset": secs} to seek relative to it
+/// POST /volume with {"volume": 0 to 100}
+/// POST /dir with {"dir": path, "play": bool}. Relative paths are from the browsing directory.
+/// GET /events, a WebSocket that sends the current state, then every event as it happens. The
+/// state is sent again if the client falls too far behind to be sent every event.
+///
+/// POSTs must have a Content-Type of application/json, even when they have no body, and requests
+/// from web pages on other sites are turned away. Otherwise any page the user visits could
+/// control the player, as browsers send simple cross site requests without asking first.
+///
+/// When a token is set, every request must carry it, either as an "Authorization: Bearer" header
+/// or as a token query parameter for browsers, which can't set headers on WebSockets. Without a
+/// token, requests must also be addressed to localhost or an IP address, so a site can't get
+/// around the check by pointing one of its own host names at us.
+pub struct HttpServer {
+    _thread: JoinHandle<()>,
+}
+
+/// A request from a client
+struct Request {
+    method: String,
+    // The path and query
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/// A request that couldn't be carried out, sent back as {"error": message}
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl HttpError {
+    fn new(status: u16, message: impl Into<String>) -> HttpError {
+        HttpError {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SeekBody {
+    position: Option<f64>,
+    offset: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VolumeBody {
+    volume: u8,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DirBody {
+    dir: String,
+    #[serde(default)]
+    play: bool,
+}
+
+#[derive(Serialize)]
+struct Dirs {
+    browsing_dir: String,
+    dirs: Vec<String>,
+    playlists: Vec<String>,
+}
+
+impl HttpServer {
+    /// Starts answering HTTP requests on the specified address. Every connection gets its own
+    /// thread, so a slow client doesn't hold up anyone else.
+    /// # Parameters
+    /// token = The token every request must carry, None to let anyone who can connect in
+    pub fn init(ctrl: Controller, address: SocketAddr, token: Option<String>) -> io::Result<HttpServer> {
+        let listener = TcpListener::bind(address)?;
+        println!("HTTP API listening on {}", address);
+        if token.is_none() && !address.ip().is_loopback() {
+            eprintln!("Warning: the HTTP API on {} has no http_token, anyone on the network can control the player", address);
+        }
+        let _thread = std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("Failed to accept HTTP client: {}", e);
+                        continue;
+                    }
+                };
+                let ctrl = ctrl.clone();
+                let token = token.clone();
+                std::thread::spawn(move || serve(ctrl, token.as_deref(), stream));
+            }
+        });
+        Ok(HttpServer {
+            _thread,
+        })
+    }
+}
+
+impl Request {
+    /// Gets the path of the request, without the query
+    fn path(&self) -> &str {
+        self.url.split('?').next().unwrap_or_default()
+    }
+
+    /// Gets the value of the first header with the specified name, which is case insensitive
+    fn header(&self, field: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(field))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Reads the body as JSON into the specified type
+    fn json_body<T: for<'de> Deserialize<'de>>(&self) -> Result<T, HttpError> {
+        serde_json::from_slice(&self.body).map_err(|e| HttpError::new(400, format!("Invalid body: {}", e)))
+    }
+}
+
+/// Answers one connection's request, or streams events to it if it asked for them
+fn serve(mut ctrl: Controller, token: Option<&str>, mut stream: TcpStream) {
+    if let Err(e) = stream.set_read_timeout(Some(REQUEST_TIMEOUT)).and_then(|_| stream.set_write_timeout(Some(REQUEST_TIMEOUT))) {
+        eprintln!("Unable to set up HTTP connection: {}", e);
+        return;
+    }
+    let res = read_request(&mut stream).and_then(|request| {
+        check_origin(&request, token.is_some())?;
+        if !is_authorized(&request, token) {
+            return Err(HttpError::new(401, "Missing or wrong token"));
+        }
+        Ok(request)
+    });
+    match res {
+        Ok(request) if request.path() == "/events" => stream_events(ctrl, stream, request),
+        Ok(request) => {
+            let res = handle(&mut ctrl, &request);
+            respond(&mut stream, res);
+        }
+        Err(e) => respond(&mut stream, Err(e)),
+    }
+}
+
+/// Reads a request's head and body. Only bodies with a Content-Length are supported. For a
+/// request without one, the body is anything the client sent after the head, which for a
+/// WebSocket is the start of its first frame.
+fn read_request(stream: &mut TcpStream) -> Result<Request, HttpError> {
+    let read_error = |e: io::Error| match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => HttpError::new(408, "Timed out waiting on the request"),
+        _ => HttpError::new(400, e.to_string()),
+    };
+    let mut buf: Vec<u8> = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let mut request = loop {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Request::new(&mut headers);
+        match parsed.parse(&buf) {
+            Ok(httparse::Status::Complete(head_len)) => {
+                break Request {
+                    method: parsed.method.unwrap_or_default().to_string(),
+                    url: parsed.path.unwrap_or_default().to_string(),
+                    headers: parsed.headers.iter()
+                        .map(|header| (header.name.to_string(), String::from_utf8_lossy(header.value).trim().to_string()))
+                        .collect(),
+                    body: buf[head_len..].to_vec(),
+                };
+            }
+            Ok(httparse::Status::Partial) => {}
+            Err(e) => return Err(HttpError::new(400, format!("Invalid request: {}", e))),
+        }
+        if buf.len() > MAX_HEAD_LEN {
+            return Err(HttpError::new(431, "Request head is too large"));
+        }
+        let len = stream.read(&mut chunk).map_err(read_error)?;
+        if len == 0 {
+            return Err(HttpError::new(400, "Connection closed part way through the request"));
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    };
+
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(HttpError::new(411, "Send the body with a Content-Length"));
+    }
+    let Some(body_len) = request.header("Content-Length") else {
+        return Ok(request);
+    };
+    let body_len: usize = body_len.parse().map_err(|_| HttpError::new(400, format!("Invalid Content-Length: {}", body_len)))?;
+    if body_len > MAX_BODY_LEN {
+        return Err(HttpError::new(413, "Request body is too large"));
+    }
+    while request.body.len() < body_len {
+        let len = stream.read(&mut chunk).map_err(read_error)?;
+        if len == 0 {
+            return Err(HttpError::new(400, "Connection closed part way through the request body"));
+        }
+        request.body.extend_from_slice(&chunk[..len]);
+    }
+    request.body.truncate(body_len);
+    Ok(request)
+}
+
+/// Carries out a request, giving back the JSON to answer with, or None for no content
+fn handle(ctrl: &mut Controller, request: &Request) -> Result<Option<serde_json::Value>, HttpError> {
+    let player_error = |e: PlayerError| HttpError::new(500, e.to_string());
+    if request.method == "POST" && request.header("Content-Type").is_none_or(|value| !is_json(value)) {
+        return Err(HttpError::new(415, "Expected a Content-Type of application/json"));
+    }
+    match (request.path(), request.method.as_str()) {
+        ("/status", "GET") => to_json(&ctrl.snapshot()).map(Some),
+        ("/dirs", "GET") => {
+            let browsing_dir = ctrl.get_browsing_dir();
+            let dirs = ctrl.get_available_dirs().map_err(|e| HttpError::new(500, e.to_string()))?;
+            let playlists = ctrl.get_available_playlists().unwrap_or_default();
+            to_json(&Dirs {
+                browsing_dir,
+                dirs,
+                playlists,
+            }).map(Some)
+        }
+        ("/play", "POST") => ctrl.play().map(|_| None).map_err(player_error),
+        ("/pause", "POST") => {
+            ctrl.pause();
+            Ok(None)
+        }
+        ("/stop", "POST") => {
+            ctrl.stop();
+            Ok(None)
+        }
+        ("/next", "POST") => ctrl.next().map(|_| None).map_err(player_error),
+        ("/previous", "POST") => ctrl.prior_track().map(|_| None).map_err(player_error),
+        ("/seek", "POST") => {
+            let res = match request.json_body::<SeekBody>()? {
+                SeekBody { position: Some(position), offset: None } => match Duration::try_from_secs_f64(position) {
+                    Ok(position) => ctrl.seek(position),
+                    Err(_) => return Err(HttpError::new(400, "Position is out of range, expected 0 or more seconds")),
+                },
+                SeekBody { position: None, offset: Some(offset) } => ctrl.seek_by(offset),
+                _ => return Err(HttpError::new(400, "Expected either a position of 0 or more seconds, or an offset in seconds")),
+            };
+            res.map(|_| None).map_err(|e| match e {
+                PlayerError::NothingPlaying => HttpError::new(409, e.to_string()),
+                e => player_error(e),
+            })
+        }
+        ("/volume", "POST") => {
+            let body: VolumeBody = request.json_body()?;
+            if body.volume > 100 {
+                return Err(HttpError::new(400, format!("Volume {} is out of range, expected 0 to 100", body.volume)));
+            }
+            ctrl.set_volume(body.volume as f32 / 100.0);
+            Ok(None)
+        }
+        ("/dir", "POST") => {
+            let body: DirBody = request.json_body()?;
+            let dir = Path::new(&ctrl.get_browsing_dir()).join(&body.dir);
+            let dir = dir.to_str().ok_or_else(|| HttpError::new(400, "Directory isn't valid UTF-8"))?;
+            ctrl.set_browsing_dir(dir, body.play).map(|_| None).map_err(|e| match e {
+                PlayerError::Io(e) if e.kind() == io::ErrorKind::NotFound => HttpError::new(404, e.to_string()),
+                PlayerError::Io(e) => HttpError::new(400, e.to_string()),
+                e => player_error(e),
+            })
+        }
+        ("/status" | "/dirs" | "/play" | "/pause" | "/stop" | "/next" | "/previous" | "/seek" | "/volume" | "/dir", method) => {
+            Err(HttpError::new(405, format!("{} is not allowed here", method)))
+        }
+        (path, _) => Err(HttpError::new(404, format!("Nothing at {}", path))),
+    }
+}
+
+/// Turns a connection into a WebSocket and sends it the current state, then every event as it
+/// happens, until the client hangs up
+fn stream_events(mut ctrl: Controller, mut stream: TcpStream, request: Request) {
+    let is_upgrade = request.header("Upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+    let Some(key) = request.header("Sec-WebSocket-Key").filter(|_| is_upgrade && request.method == "GET") else {
+        respond(&mut stream, Err(HttpError::new(400, "Expected a WebSocket upgrade request")));
+        return;
+    };
+    let response = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                           tungstenite::handshake::derive_accept_key(key.as_bytes()));
+    // From here on, reads only wait a moment so we can get back to sending events
+    if stream.write_all(response.as_bytes()).and_then(|_| stream.set_read_timeout(Some(EVENT_POLL_INTERVAL))).is_err() {
+        return;
+    }
+    let mut socket = WebSocket::from_partially_read(stream, request.body, Role::Server, None);
+
+    // Subscribe before taking the snapshot so nothing that happens in between is missed
+    let events = ctrl.subscribe();
+    if socket.send(status_message(&ctrl)).is_err() {
+        return;
+    }
+    let mut last_sent = Instant::now();
+    loop {
+        for event in events.receiver().try_iter() {
+            let message = match event {
+                // Rather than pass on that events were missed, send the state the client needs
+                PlayerEvent::Resync => status_message(&ctrl),
+                event => match serde_json::to_string(&event) {
+                    Ok(event) => Message::text(event),
+                    Err(e) => {
+                        eprintln!("Unable to send event {:?}: {}", event, e);
+                        continue;
+                    }
+                },
+            };
+            if socket.send(message).is_err() {
+                return;
+            }
+            last_sent = Instant::now();
+        }
+        if last_sent.elapsed() >= PING_INTERVAL {
+            if socket.send(Message::Ping(Default::default())).is_err() {
+                return;
+            }
+            last_sent = Instant::now();
+        }
+
+        // We don't expect anything from the client, but reading is how its pings get answered,
+        // its close gets acknowledged, and how we find out it has gone away. The replies are
+        // queued up by read and sent by flush.
+        let res = socket.read().and_then(|_| socket.flush());
+        match res {
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+/// Makes the message with the full current state, sent when the stream starts and whenever the
+/// client has to catch up
+fn status_message(ctrl: &Controller) -> Message {
+    Message::text(json!({"event": "status", "data": ctrl.snapshot()}).to_string())
+}
+
+/// Sends the answer to a request and closes the connection, logging rather than failing if the
+/// client has gone away
+fn respond(stream: &mut TcpStream, res: Result<Option<serde_json::Value>, HttpError>) {
+    let (status, body) = match res {
+        Ok(Some(value)) => (200, value.to_string()),
+        Ok(None) => (204, String::new()),
+        Err(e) => (e.status, json!({"error": e.message}).to_string()),
+    };
+    let mut response = format!("HTTP/1.1 {} {}\r\nConnection: close\r\n", status, reason_phrase(status));
+    if !body.is_empty() {
+        response.push_str(&format!("Content-Type: application/json\r\nContent-Length: {}\r\n", body.len()));
+    }
+    response.push_str("\r\n");
+    response.push_str(&body);
+    if let Err(e) = stream.write_all(response.as_bytes()) {
+        eprintln!("Unable to answer HTTP request: {}", e);
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<serde_json::Value, HttpError> {
+    serde_json::to_value(value).map_err(|e| HttpError::new(500, e.to_string()))
+}
+
+/// Returns true if a Content-Type is JSON, ignoring any charset after it
+fn is_json(content_type: &str) -> bool {
+    content_type.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case("application/json")
+}
+
+/// Turns away requests made by web pages from other sites. Browsers always send an Origin with
+/// those, while scripts and kiosks that aren't browsers don't send one at all.
+/// # Parameters
+/// has_token = True if requests need a token. If they don't, the Host must be localhost or an IP
+///             address, as a site's own host name could be made to point at us.
+fn check_origin(request: &Request, has_token: bool) -> Result<(), HttpError> {
+    let host = request.header("Host").unwrap_or_default();
+    if !has_token && !is_local_host(host) {
+        return Err(HttpError::new(403, format!("Requests must be addressed to localhost or an IP address, not {}", host)));
+    }
+    match request.header("Origin") {
+        Some(origin) if !origin.eq_ignore_ascii_case(&format!("http://{}", host)) => {
+            Err(HttpError::new(403, format!("Requests from {} are not allowed", origin)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Returns true if a Host header names localhost or an IP address, with or without a port
+fn is_local_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    name.eq_ignore_ascii_case("localhost") || name.parse::<std::net::IpAddr>().is_ok()
+}
+
+/// Checks the request carries the token, if there is one
+fn is_authorized(request: &Request, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    let from_header = request.headers.iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Authorization"))
+        .filter_map(|(_, value)| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+    let from_query = request.url.split_once('?')
+        .map(|(_, query)| query)
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|param| param.strip_prefix("token="))
+        .map(file_utils::percent_decode);
+    from_header.chain(from_query).any(|given| same_token(given.trim(), token))
+}
+
+/// Compares tokens in the same time no matter where they differ, so the time taken gives nothing
+/// away about the real token
+fn same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// Everything a key can do in the terminal UI
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    SelectUp,
+    SelectDown,
+    PageUp,
+    PageDown,
+    OpenDir,
+    ParentDir,
+    NextMusicDir,
+    PlayDir,
+    Enqueue,
+    EnqueueNext,
+    ClearQueue,
+    SaveQueue,
+    PlayPause,
+    Stop,
+    Next,
+    Previous,
+    SeekForward,
+    SeekBack,
+    VolumeUp,
+    VolumeDown,
+    Mute,
+    Repeat,
+    Shuffle,
+    Recursive,
+    Rescan,
+    Quit,
+}
+
+/// Every action, with the name it goes by in the config file and the keys it is bound to unless
+/// the config file says otherwise
+const DEFAULT_BINDINGS: [(Action, &str, &[&str]); 26] = [
+    (Action::SelectUp, "select_up", &["up"]),
+    (Action::SelectDown, "select_down", &["down"]),
+    (Action::PageUp, "page_up", &["pageup"]),
+    (Action::PageDown, "page_down", &["pagedown"]),
+    (Action::OpenDir, "open", &["enter", "right"]),
+    (Action::ParentDir, "parent", &["backspace", "left"]),
+    (Action::NextMusicDir, "next_music_dir", &["g"]),
+    (Action::PlayDir, "play_dir", &["d"]),
+    (Action::Enqueue, "enqueue", &["a"]),
+    (Action::EnqueueNext, "enqueue_next", &["i"]),
+    (Action::ClearQueue, "clear_queue", &["e"]),
+    (Action::SaveQueue, "save_queue", &["w"]),
+    (Action::PlayPause, "play_pause", &["space"]),
+    (Action::Stop, "stop", &["s"]),
+    (Action::Next, "next", &["n"]),
+    (Action::Previous, "previous", &["p"]),
+    (Action::SeekForward, "seek_forward", &["f"]),
+    (Action::SeekBack, "seek_back", &["b"]),
+    (Action::VolumeUp, "volume_up", &["+", "="]),
+    (Action::VolumeDown, "volume_down", &["-"]),
+    (Action::Mute, "mute", &["m"]),
+    (Action::Repeat, "repeat", &["r"]),
+    (Action::Shuffle, "shuffle", &["z"]),
+    (Action::Recursive, "recursive", &["c"]),
+    (Action::Rescan, "rescan", &["u"]),
+    (Action::Quit, "quit", &["q", "x", "esc"]),
+];
+
+/// A key along with the Ctrl and Alt modifiers held down with it
+type Key = (KeyCode, KeyModifiers);
+
+/// Maps key presses to actions
+#[derive(Debug, Clone)]
+pub struct KeyBindings {
+    actions: HashMap<Key, Action>,
+    // The keys bound to each action, in the order they were given, for showing in the help pane
+    keys: HashMap<Action, Vec<Key>>,
+}
+
+impl KeyBindings {
+    /// Builds the key bindings, starting from the defaults and replacing the keys of any action
+    /// listed in overrides. Overrides are keyed by the action's config file name.
+    pub fn init(overrides: &HashMap<String, Vec<String>>) -> Result<KeyBindings, String> {
+        for action_name in overrides.keys() {
+            if !DEFAULT_BINDINGS.iter().any(|(_, name, _)| name == action_name) {
+                let names: Vec<&str> = DEFAULT_BINDINGS.iter().map(|(_, name, _)| *name).collect();
+                return Err(format!("unknown action '{}', expected one of: {}", action_name, names.join(", ")));
+            }
+        }
+
+        let mut bindings = KeyBindings {
+            actions: HashMap::new(),
+            keys: HashMap::new(),
+        };
+        for (action, name, default_keys) in DEFAULT_BINDINGS {
+            let key_names: Vec<&str> = match overrides.get(name) {
+                Some(key_names) => key_names.iter().map(String::as_str).collect(),
+                None => default_keys.to_vec(),
+            };
+            for key_name in key_names {
+                let key = parse_key(key_name).map_err(|e| format!("{}: {}", name, e))?;
+                if let Some(other) = bindings.actions.insert(key, action) {
+                    return Err(format!("key '{}' is bound to both {} and {}", key_name, action_name(other), name));
+                }
+                bindings.keys.entry(action).or_default().push(key);
+            }
+        }
+        Ok(bindings)
+    }
+
+    /// Gets the action bound to the specified key press, if any. Shift is left out when matching,
+    /// as it is already part of the character typed.
+    pub fn action(&self, key: KeyEvent) -> Option<Action> {
+        self.actions.get(&(key.code, key.modifiers & (KeyModifiers::CONTROL | KeyModifiers::ALT))).copied()
+    }
+
+    /// Gets the name of the first key bound to the specified action, for showing in the help pane.
+    /// Actions with no keys show as a dash.
+    pub fn key_name(&self, action: Action) -> String {
+        match self.keys.get(&action).and_then(|keys| keys.first()) {
+            Some(key) => display_key(*key),
+            None => "-".to_string(),
+        }
+    }
+}
+
+fn action_name(action: Action) -> &'static str {
+    DEFAULT_BINDINGS.iter()
+        .find(|(other, _, _)| *other == action)
+        .map(|(_, name, _)| *name)
+        .unwrap_or_default()
+}
+
+/// Parses a key name from the config file. Names are a single character, like "n" or "+", or one of
+/// the special keys like "space", "enter", "up", or "f1". Either can start with "ctrl+" or "alt+",
+/// like "ctrl+n". Ctrl+C always quits, so it can't be bound.
+fn parse_key(key_name: &str) -> Result<Key, String> {
+    let mut modifiers = KeyModifiers::NONE;
+    let mut name = key_name;
+    loop {
+        let lower = name.to_ascii_lowercase();
+        let (modifier, rest) = if lower.starts_with("ctrl+") && name.len() > 5 {
+            (KeyModifiers::CONTROL, &name[5..])
+        } else if lower.starts_with("alt+") && name.len() > 4 {
+            (KeyModifiers::ALT, &name[4..])
+        } else {
+            break;
+        };
+        modifiers |= modifier;
+        name = rest;
+    }
+    let code = parse_key_code(name).map_err(|_| format!("unknown key '{}'", key_name))?;
+    if modifiers.contains(KeyModifiers::CONTROL) && matches!(code, KeyCode::Char('c' | 'C')) {
+        return Err(format!("key '{}' always quits and can't be bound", key_name));
+    }
+    Ok((code, modifiers))
+}
+
+/// Parses a key name without any modifiers. See parse_key.
+fn parse_key_code(key_name: &str) -> Result<KeyCode, ()> {
+    let mut chars = key_name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(KeyCode::Char(c));
+    }
+    let key = match key_name.to_ascii_lowercase().as_str() {
+        "space" => KeyCode::Char(' '),
+        "enter" | "return" => KeyCode::Enter,
+        "esc" | "escape" => KeyCode::Esc,
+        "tab" => KeyCode::Tab,
+        "backspace" => KeyCode::Backspace,
+        "delete" | "del" => KeyCode::Delete,
+        "insert" | "ins" => KeyCode::Insert,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pageup" | "pgup" => KeyCode::PageUp,
+        "pagedown" | "pgdn" => KeyCode::PageDown,
+        name => match name.strip_prefix('f').and_then(|number| number.parse::<u8>().ok()) {
+            Some(number) if (1..=12).contains(&number) => KeyCode::F(number),
+            _ => return Err(()),
+        },
+    };
+    Ok(key)
+}
+
+/// Gets a short name for a key, for showing in the help pane
+fn display_key((code, modifiers): Key) -> String {
+    let mut name = String::new();
+    if modifiers.contains(KeyModifiers::CONTROL) {
+        name.push_str("Ctrl+");
+    }
+    if modifiers.contains(KeyModifiers::ALT) {
+        name.push_str("Alt+");
+    }
+    name.push_str(&display_key_code(code));
+    name
+}
+
+fn display_key_code(key: KeyCode) -> String {
+    match key {
+        KeyCode::Char(' ') => "Space".to_string(),
+        KeyCode::Char(c) => c.to_string(),
+        KeyCode::Enter => "Enter".to_string(),
+        KeyCode::Esc => "Esc".to_string(),
+        KeyCode::Tab => "Tab".to_string(),
+        KeyCode::Backspace => "Backspace".to_string(),
+        KeyCode::Delete => "Delete".to_string(),
+        KeyCode::Insert => "Insert".to_string(),
+        KeyCode::Up => "Up".to_string(),
+        KeyCode::Down => "Down".to_string(),
+        KeyCode::Left => "Left".to_string(),
+        KeyCode::Right => "Right".to_string(),
+        KeyCode::Home => "Home".to_string(),
+        KeyCode::End => "End".to_string(),
+        KeyCode::PageUp => "PgUp".to_string(),
+        KeyCode::PageDown => "PgDn".to_string(),
+        KeyCode::F(number) => format!("F{}", number),
+        _ => "?".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overrides(bindings: &[(&str, &[&str])]) -> HashMap<String, Vec<String>> {
+        bindings.iter()
+            .map(|(action, keys)| (action.to_string(), keys.iter().map(|key| key.to_string()).collect()))
+            .collect()
+    }
+
+    fn press(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let bindings = KeyBindings::init(&HashMap::new()).unwrap();
+        assert_eq!(bindings.action(press(KeyCode::Char(' '), KeyModifiers::NONE)), Some(Action::PlayPause));
+        assert_eq!(bindings.action(press(KeyCode::Esc, KeyModifiers::NONE)), Some(Action::Quit));
+        assert_eq!(bindings.key_name(Action::Quit), "q");
+    }
+
+    #[test]
+    fn overrides_replace_the_default_keys() {
+        let bindings = KeyBindings::init(&overrides(&[("next", &["ctrl+n", "F5"]), ("quit", &[])])).unwrap();
+        assert_eq!(bindings.action(press(KeyCode::Char('n'), KeyModifiers::CONTROL)), Some(Action::Next));
+        assert_eq!(bindings.action(press(KeyCode::F(5), KeyModifiers::NONE)), Some(Action::Next));
+        assert_eq!(bindings.action(press(KeyCode::Char('n'), KeyModifiers::NONE)), None);
+        assert_eq!(bindings.action(press(KeyCode::Char('q'), KeyModifiers::NONE)), None);
+        assert_eq!(bindings.key_name(Action::Next), "Ctrl+n");
+        assert_eq!(bindings.key_name(Action::Quit), "-");
+    }
+
+    #[test]
+    fn shift_is_ignored_but_ctrl_and_alt_are_not() {
+        let bindings = KeyBindings::init(&overrides(&[("mute", &["alt+ctrl+M"])])).unwrap();
+        let both = KeyModifiers::CONTROL | KeyModifiers::ALT;
+        assert_eq!(bindings.action(press(KeyCode::Char('M'), both | KeyModifiers::SHIFT)), Some(Action::Mute));
+        assert_eq!(bindings.action(press(KeyCode::Char('M'), KeyModifiers::CONTROL)), None);
+        assert_eq!(bindings.action(press(KeyCode::Char('+'), KeyModifiers::SHIFT)), Some(Action::VolumeUp));
+        assert_eq!(bindings.key_name(Action::Mute), "Ctrl+Alt+M");
+    }
+
+    #[test]
+    fn ctrl_c_cant_be_bound() {
+        for key in ["ctrl+c", "Ctrl+C", "alt+ctrl+c"] {
+            let error = KeyBindings::init(&overrides(&[("stop", &[key])])).unwrap_err();
+            assert!(error.contains("always quits"), "{}: {}", key, error);
+        }
+        // Without Ctrl it's just a letter
+        assert!(KeyBindings::init(&overrides(&[("stop", &["alt+c"]), ("recursive", &["C"])])).is_ok());
+    }
+
+    #[test]
+    fn unknown_actions_and_keys_are_rejected() {
+        let error = KeyBindings::init(&overrides(&[("dance", &["d"])])).unwrap_err();
+        assert!(error.starts_with("unknown action 'dance'"), "{}", error);
+        for key in ["", "f13", "ctrl+", "hyper+x", "pgupp"] {
+            let error = KeyBindings::init(&overrides(&[("stop", &[key])])).unwrap_err();
+            assert_eq!(error, format!("stop: unknown key '{}'", key));
+        }
+    }
+
+    #[test]
+    fn keys_cant_be_bound_twice() {
+        // Clashing with a default binding
+        let error = KeyBindings::init(&overrides(&[("stop", &["n"])])).unwrap_err();
+        assert_eq!(error, "key 'n' is bound to both stop and next");
+        // Different names for the same key
+        let error = KeyBindings::init(&overrides(&[("stop", &["space"]), ("play_pause", &[" "])])).unwrap_err();
+        assert!(error.contains("bound to both"), "{}", error);
+    }
+}
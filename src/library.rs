@@ -0,0 +1,268 @@
+/// An on-disk index of the music files we have seen, so large collections don't have to be walked
+/// and have their tags read on every play
+#[allow(clippy::module_inception)]
+pub mod library {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::time::SystemTime;
+    use serde::{Deserialize, Serialize};
+    use crate::file_utils::file_utils;
+    use crate::metadata::metadata::{self, TrackMetadata};
+    use crate::saved_state::saved_state::APP_DIR_NAME;
+
+    const INDEX_FILE_NAME: &str = "library.json";
+
+    /// What we know about a directory the last time we read it
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct IndexedDir {
+        modified: SystemTime,
+        music_files: Vec<PathBuf>,
+        sub_dirs: Vec<PathBuf>,
+    }
+
+    /// What we know about a music file the last time we read its tags
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct IndexedFile {
+        size: u64,
+        modified: SystemTime,
+        tags: TrackMetadata,
+    }
+
+    /// The index itself. Directories are only re-read when their modified time changes, which is
+    /// what happens when files are added to or removed from them. Files only have their tags re-read
+    /// when their size or modified time changes. Use rescan to start over from scratch. Changes are
+    /// kept in memory until save is called.
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    pub struct LibraryIndex {
+        dirs: HashMap<PathBuf, IndexedDir>,
+        files: HashMap<PathBuf, IndexedFile>,
+        // The file filter the directories were read with. They are read again if it changes.
+        #[serde(default)]
+        file_filter: String,
+        // Where the index is saved, None if there is nowhere to save it
+        #[serde(skip)]
+        index_file: Option<PathBuf>,
+        // True if the index has changed since it was loaded or saved
+        #[serde(skip)]
+        dirty: bool,
+    }
+
+    /// Gets the file the index is saved in. Follows the XDG base directory spec, so this is in
+    /// $XDG_CACHE_HOME, or ~/.cache if $XDG_CACHE_HOME isn't set.
+    fn index_file() -> Option<PathBuf> {
+        let base_dir = match std::env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+        };
+        Some(base_dir.join(APP_DIR_NAME).join(INDEX_FILE_NAME))
+    }
+
+    impl LibraryIndex {
+        /// Loads the index saved by the last run. Starts with an empty index if there isn't one or
+        /// it can't be read.
+        pub fn load() -> LibraryIndex {
+            let index_file = index_file();
+            let mut index: LibraryIndex = index_file.as_ref()
+                .and_then(|file| fs::read_to_string(file).ok())
+                .and_then(|contents| match serde_json::from_str(&contents) {
+                    Ok(index) => Some(index),
+                    Err(e) => {
+                        eprintln!("Ignoring unreadable library index: {}", e);
+                        None
+                    }
+                })
+                .unwrap_or_default();
+            index.index_file = index_file;
+
+            // A change to the supported extensions or exclude patterns changes which files each
+            // directory holds
+            let file_filter = file_utils::file_filter_description();
+            if index.file_filter != file_filter {
+                index.dirs.clear();
+                index.file_filter = file_filter;
+                index.dirty = true;
+            }
+            index
+        }
+
+        /// Writes the index to disk, if it has changed
+        pub fn save(&mut self) {
+            if !self.dirty {
+                return;
+            }
+            let Some(index_file) = self.index_file.clone() else {
+                return;
+            };
+
+            // Write to a temporary file first so a crash part way through doesn't lose the index
+            let tmp_file = index_file.with_extension("json.tmp");
+            let res = serde_json::to_string(self)
+                .map_err(io::Error::from)
+                .and_then(|contents| {
+                    if let Some(dir) = index_file.parent() {
+                        fs::create_dir_all(dir)?;
+                    }
+                    fs::write(&tmp_file, contents)?;
+                    fs::rename(&tmp_file, &index_file)
+                });
+            match res {
+                Ok(_) => self.dirty = false,
+                Err(e) => eprintln!("Failed to save library index to {}: {}", index_file.display(), e),
+            }
+        }
+
+        /// Forgets everything in the index and re-reads the specified directory from scratch.
+        /// Returns the number of music files found.
+        pub fn rescan(&mut self, dir: &str, recursive: bool) -> io::Result<usize> {
+            self.dirs.clear();
+            self.files.clear();
+            self.dirty = true;
+            Ok(self.list_music_files(dir, recursive)?.len())
+        }
+
+        /// Gets a list of all the music files in the specified directory, updating the index from
+        /// disk where it is out of date.
+        /// # Parameters
+        /// dir_to_scan = The absolute or relative path to the directory you want to query.
+        /// recursive = If false, this method only returns music files found in the specified directory.
+        ///             If true, this method also includes files found in subdirectories.
+        pub fn list_music_files(&mut self, dir_to_scan: &str, recursive: bool) -> io::Result<Vec<PathBuf>> {
+            let top_dir = PathBuf::from(dir_to_scan);
+            let mut music_files = Vec::new();
+            let mut dirs_to_scan = vec![top_dir.clone()];
+            while let Some(dir) = dirs_to_scan.pop() {
+                let indexed_dir = match self.update_dir(&dir) {
+                    Ok(indexed_dir) => indexed_dir,
+                    // One unreadable subdirectory shouldn't hide the rest of the music
+                    Err(e) if dir != top_dir => {
+                        eprintln!("Skipping unreadable directory {}: {}", dir.display(), e);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                music_files.extend(indexed_dir.music_files);
+                if recursive {
+                    dirs_to_scan.extend(indexed_dir.sub_dirs);
+                }
+            }
+            println!("Found {} music files in {}", music_files.len(), dir_to_scan);
+            Ok(music_files)
+        }
+
+        /// Gets the tags for the specified file, if it is in the index
+        pub fn tags(&self, file_path: &Path) -> Option<&TrackMetadata> {
+            self.files.get(file_path).map(|indexed_file| &indexed_file.tags)
+        }
+
+        /// Gets the tags for the specified music file, only reading them from the file if it isn't
+        /// in the index or has changed since it was indexed
+        pub fn read_tags(&mut self, file_path: &Path) -> io::Result<TrackMetadata> {
+            self.update_file(file_path)?;
+            Ok(self.files[file_path].tags.clone())
+        }
+
+        /// Records the length of a music file that doesn't record it in its tags, once it has been
+        /// worked out some other way, so it doesn't have to be worked out again
+        pub fn set_duration(&mut self, file_path: &Path, duration: u32) {
+            if let Some(indexed_file) = self.files.get_mut(file_path) {
+                if indexed_file.tags.duration.is_none() {
+                    indexed_file.tags.duration = Some(duration);
+                    self.dirty = true;
+                }
+            }
+        }
+
+        /// Sorts the specified music files into album order using the tags in the index
+        pub fn sort_by_tags(&self, files: &mut [PathBuf]) {
+            let untagged = TrackMetadata::default();
+            files.sort_by(|a, b| {
+                let a_tags = self.tags(a).unwrap_or(&untagged);
+                let b_tags = self.tags(b).unwrap_or(&untagged);
+                a_tags.album_order(b_tags).then(a.cmp(b))
+            });
+        }
+
+        /// Brings the index entry for a single directory up to date and returns it. Entries that
+        /// can't be read are left out.
+        fn update_dir(&mut self, dir: &Path) -> io::Result<IndexedDir> {
+            let modified = fs::metadata(dir)?.modified()?;
+            if let Some(indexed_dir) = self.dirs.get(dir).filter(|indexed_dir| indexed_dir.modified == modified).cloned() {
+                // Editing a file's tags doesn't change the directory, so each file still needs a look
+                for file_path in &indexed_dir.music_files {
+                    if let Err(e) = self.update_file(file_path) {
+                        eprintln!("Unable to check {}: {}", file_path.display(), e);
+                    }
+                }
+                return Ok(indexed_dir);
+            }
+
+            let mut indexed_dir = IndexedDir {
+                modified,
+                music_files: Vec::new(),
+                sub_dirs: Vec::new(),
+            };
+            for entry in fs::read_dir(dir)? {
+                let (path, file_type) = match entry.and_then(|entry| Ok((entry.path(), entry.file_type()?))) {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        eprintln!("Skipping unreadable entry in {}: {}", dir.display(), e);
+                        continue;
+                    }
+                };
+                if file_type.is_dir() {
+                    if !file_utils::is_excluded(&path) {
+                        indexed_dir.sub_dirs.push(path);
+                    }
+                } else if file_utils::is_supported_audio_file(&path) {
+                    match self.update_file(&path) {
+                        Ok(_) => indexed_dir.music_files.push(path),
+                        Err(e) => eprintln!("Skipping unreadable file {}: {}", path.display(), e),
+                    }
+                }
+            }
+
+            // Forget about anything that has been removed from the directory since we last read it
+            if let Some(old_dir) = self.dirs.remove(dir) {
+                for old_file in old_dir.music_files.iter().filter(|f| !indexed_dir.music_files.contains(f)) {
+                    self.files.remove(old_file);
+                }
+                for old_sub_dir in old_dir.sub_dirs.iter().filter(|d| !indexed_dir.sub_dirs.contains(d)) {
+                    self.forget_dir(old_sub_dir);
+                }
+            }
+
+            self.dirs.insert(dir.to_path_buf(), indexed_dir.clone());
+            self.dirty = true;
+            Ok(indexed_dir)
+        }
+
+        /// Brings the index entry for a single music file up to date
+        fn update_file(&mut self, file_path: &Path) -> io::Result<()> {
+            let file_metadata = fs::metadata(file_path)?;
+            let size = file_metadata.len();
+            let modified = file_metadata.modified()?;
+            if let Some(indexed_file) = self.files.get(file_path) {
+                if indexed_file.size == size && indexed_file.modified == modified {
+                    return Ok(());
+                }
+            }
+
+            // A file with unreadable tags is still playable, so index it without them
+            let tags = metadata::read_metadata(file_path).unwrap_or_else(|_| TrackMetadata {
+                path: file_path.to_path_buf(),
+                ..Default::default()
+            });
+            self.files.insert(file_path.to_path_buf(), IndexedFile { size, modified, tags });
+            self.dirty = true;
+            Ok(())
+        }
+
+        /// Removes a directory, and everything under it, from the index
+        fn forget_dir(&mut self, dir: &Path) {
+            self.dirs.retain(|path, _| !path.starts_with(dir));
+            self.files.retain(|path, _| !path.starts_with(dir));
+        }
+    }
+}
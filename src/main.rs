@@ -1,20 +1,53 @@
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use clap::Parser;
 use controller::Controller;
 use cli::Cli;
+use args::Args;
+use config::Config;
+use mpris::Mpris;
+use mpd::MpdServer;
+use http_api::HttpServer;
 
 mod music_player;
 mod file_utils;
+mod metadata;
+mod library;
 mod controller;
 mod cli;
 mod settings_changed;
+mod saved_state;
+mod args;
+mod config;
+mod key_bindings;
+mod event_bus;
+mod player_event;
+mod mpris;
+mod mpd;
+mod http_api;
 
 fn main() {
+    let args: Args = Args::parse();
+    let config: Config = match Config::load(args.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
     println!("Application starting...");
+    if let Some(path) = &config.path {
+        println!("Using config file {}", path.display());
+    }
+
+    // Decide which files are music files before anything reads a directory
+    file_utils::file_utils::set_file_filter(config.extensions.clone(), config.exclude.clone());
 
     // Get a listing of all music files available
-    // let starting_dir : &str = "/home/jonathan/tmp";
-    let starting_dir : &str = "/home/jonathan/Music";
+    let starting_dir: String = args.starting_dir(&config).to_string_lossy().to_string();
 
-    let ctrl: Controller = Controller::init(starting_dir.to_string());  
+    let ctrl: Controller = Controller::init(starting_dir.clone(), args.initial_settings(&config));
 
     // get a list of all subdirectories
     let sub_dirs_res: Result<Vec<String>, std::io::Error> = ctrl.get_available_dirs();
@@ -27,7 +60,43 @@ fn main() {
         Err(_) => println!("No sub directories found"),
     }
 
-    let cli: Cli = Cli::init(ctrl);
+    // Let desktop media keys and media widgets control playback
+    let _mpris: Option<Mpris> = if config.mpris {
+        match Mpris::init(ctrl.clone()) {
+            Ok(mpris) => Some(mpris),
+            Err(e) => {
+                eprintln!("Unable to offer media controls over D-Bus: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // Let MPD clients browse and control the music directory
+    let _mpd_server: Option<MpdServer> = config.mpd.and_then(|address| {
+        MpdServer::init(ctrl.clone(), address, PathBuf::from(&starting_dir))
+            .map_err(|e| eprintln!("Unable to start the MPD server on {}: {}", address, e))
+            .ok()
+    });
+
+    // Let kiosks and scripts control the player over HTTP
+    let _http_server: Option<HttpServer> = config.http.and_then(|address| {
+        HttpServer::init(ctrl.clone(), address, config.http_token.clone())
+            .map_err(|e| eprintln!("Unable to start the HTTP API on {}: {}", address, e))
+            .ok()
+    });
+
+    // Being told to exit, or losing the terminal, closes the UI the same way quitting does, so
+    // the terminal is put back and the session is saved
+    let quit = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGHUP, signal_hook::consts::SIGINT] {
+        if let Err(e) = signal_hook::flag::register(signal, Arc::clone(&quit)) {
+            eprintln!("Unable to handle signal {}: {}", signal, e);
+        }
+    }
+
+    let cli: Cli = Cli::init(ctrl, args.autoplay(&config), config.key_bindings, config.music_dirs, quit);
     loop {
         if cli.is_done() {
             println!("Closing gracefully");
@@ -0,0 +1,160 @@
+/// Utilities for reading the tags (title, artist, etc.) stored inside music files
+#[allow(clippy::module_inception)]
+pub mod metadata {
+    use std::cmp::Ordering;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+    use serde::{Deserialize, Serialize};
+    use symphonia::core::codecs::CodecParameters;
+    use symphonia::core::formats::FormatOptions;
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey};
+    use symphonia::core::probe::{Hint, ProbeResult};
+    use symphonia::core::units::TimeBase;
+
+    /// Everything we know about a single music file
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct TrackMetadata {
+        pub path: PathBuf,
+        pub title: Option<String>,
+        pub artist: Option<String>,
+        pub album: Option<String>,
+        pub track_number: Option<u32>,
+        pub disc_number: Option<u32>,
+        pub year: Option<i32>,
+        pub genre: Option<String>,
+        // Length of the song in whole seconds, if the file says
+        pub duration: Option<u32>,
+    }
+
+    impl TrackMetadata {
+        /// Gets a short human readable name for the track, "Artist – Title" when the tags have
+        /// both, falling back to the file name when they don't
+        pub fn display_name(&self) -> String {
+            let file_name = || self.path.file_stem().and_then(OsStr::to_str).unwrap_or_default().to_string();
+            match (&self.artist, &self.title) {
+                (Some(artist), Some(title)) => format!("{} – {}", artist, title),
+                (None, Some(title)) => title.clone(),
+                _ => file_name(),
+            }
+        }
+
+        /// Orders tracks the way they appear on their albums: by album, then disc, then track number,
+        /// then path for anything untagged
+        pub fn album_order(&self, other: &TrackMetadata) -> Ordering {
+            self.album.cmp(&other.album)
+                .then(self.disc_number.cmp(&other.disc_number))
+                .then(self.track_number.cmp(&other.track_number))
+                .then(self.path.cmp(&other.path))
+        }
+    }
+
+    /// Reads the tags and duration out of the specified music file. Understands ID3v2 (mp3),
+    /// Vorbis comments (ogg), FLAC tags, and RIFF INFO (wav). Missing tags are left as None.
+    pub fn read_metadata(file_path: &Path) -> io::Result<TrackMetadata> {
+        let mut probed = probe(file_path)?;
+
+        let mut track_metadata = TrackMetadata {
+            path: file_path.to_path_buf(),
+            ..Default::default()
+        };
+
+        // Tags can live in front of the container (like ID3v2 in front of an mp3) or inside it
+        // (like Vorbis comments in an ogg). Read both, letting the container's tags win.
+        if let Some(metadata) = probed.metadata.get() {
+            if let Some(revision) = metadata.current() {
+                apply_tags(&mut track_metadata, revision);
+            }
+        }
+        if let Some(revision) = probed.format.metadata().current() {
+            apply_tags(&mut track_metadata, revision);
+        }
+
+        if let Some(duration) = probed.format.default_track().and_then(|track| recorded_duration(&track.codec_params)) {
+            track_metadata.duration = Some(duration.as_secs().try_into().unwrap_or(u32::MAX));
+        }
+
+        Ok(track_metadata)
+    }
+
+    /// Works out how long the specified music file is. Uses the length recorded in the file when
+    /// there is one (the Xing or VBRI header of a VBR mp3, a flac's STREAMINFO, or the last page of
+    /// an ogg), otherwise adds up the length of every frame in the file. Returns None if neither
+    /// works.
+    pub fn read_duration(file_path: &Path) -> Option<Duration> {
+        let mut probed = probe(file_path).ok()?;
+        let track = probed.format.default_track()?;
+        if let Some(duration) = recorded_duration(&track.codec_params) {
+            return Some(duration);
+        }
+
+        // Frames are read without being decoded, so this is much quicker than playing the file
+        let track_id = track.id;
+        let time_base = track.codec_params.time_base
+            .or_else(|| track.codec_params.sample_rate.map(|rate| TimeBase::new(1, rate)))?;
+        let mut total_ts: u64 = 0;
+        // Reading stops at the end of the file, or at the first frame that can't be read
+        while let Ok(packet) = probed.format.next_packet() {
+            if packet.track_id() == track_id {
+                total_ts += packet.dur;
+            }
+        }
+        if total_ts == 0 {
+            return None;
+        }
+        let time = time_base.calc_time(total_ts);
+        Some(Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac))
+    }
+
+    /// Gets the length of a track from its codec parameters, if the file records it
+    fn recorded_duration(params: &CodecParameters) -> Option<Duration> {
+        let time = params.time_base?.calc_time(params.n_frames?);
+        Some(Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac))
+    }
+
+    /// Opens the specified music file and works out what format it is in
+    fn probe(file_path: &Path) -> io::Result<ProbeResult> {
+        let file = fs::File::open(file_path)?;
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+        // Let the probe know the extension so it tries the most likely format first
+        let mut hint = Hint::new();
+        if let Some(ext) = file_path.extension().and_then(OsStr::to_str) {
+            hint.with_extension(ext);
+        }
+
+        symphonia::default::get_probe()
+            .format(&hint, mss, &FormatOptions::default(), &MetadataOptions::default())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn apply_tags(track_metadata: &mut TrackMetadata, revision: &MetadataRevision) {
+        for tag in revision.tags() {
+            // RIFF INFO strings are often padded out with NULs
+            let value = tag.value.to_string().trim_matches(|c: char| c == '\0' || c.is_whitespace()).to_string();
+            if value.is_empty() {
+                continue;
+            }
+            match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => track_metadata.title = Some(value),
+                Some(StandardTagKey::Artist) => track_metadata.artist = Some(value),
+                Some(StandardTagKey::Album) => track_metadata.album = Some(value),
+                Some(StandardTagKey::TrackNumber) => track_metadata.track_number = parse_number(&value),
+                Some(StandardTagKey::DiscNumber) => track_metadata.disc_number = parse_number(&value),
+                Some(StandardTagKey::Date) | Some(StandardTagKey::ReleaseDate) => {
+                    track_metadata.year = value.get(..4).and_then(|year| year.parse().ok());
+                }
+                Some(StandardTagKey::Genre) => track_metadata.genre = Some(value),
+                _ => {}
+            }
+        }
+    }
+
+    /// Parses track and disc numbers, which are often written as "3/12" meaning 3 of 12
+    fn parse_number(value: &str) -> Option<u32> {
+        value.split('/').next()?.trim().parse().ok()
+    }
+}
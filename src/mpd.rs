@@ -0,0 +1,687 @@
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use crossbeam_channel::{select, unbounded, Receiver};
+use crate::controller::Controller;
+use crate::event_bus::Subscription;
+use crate::file_utils::file_utils;
+use crate::metadata::metadata::TrackMetadata;
+use crate::player_event::{Modes, PlaybackState, PlayerEvent};
+use crate::settings_changed::RepeatMode;
+
+/// The version of the MPD protocol we claim to speak. Clients use it to decide which commands
+/// they can send.
+const PROTOCOL_VERSION: &str = "0.23.0";
+
+/// Every command we handle, for the commands command
+const COMMANDS: [&str; 33] = [
+    "add", "clear", "close", "command_list_begin", "command_list_end", "command_list_ok_begin",
+    "commands", "currentsong", "delete", "deleteid", "idle", "lsinfo", "move", "moveid", "next",
+    "noidle", "notcommands", "pause", "ping", "play", "playid", "playlistinfo", "plchanges",
+    "previous", "random", "repeat", "seekcur", "setvol", "single", "status", "stop", "tagtypes",
+    "volume",
+];
+
+// Error codes from the MPD protocol, sent back in ACK lines
+const ACK_ERROR_ARG: u32 = 2;
+const ACK_ERROR_UNKNOWN: u32 = 5;
+const ACK_ERROR_NO_EXIST: u32 = 50;
+const ACK_ERROR_SYSTEM: u32 = 52;
+const ACK_ERROR_PLAYER_SYNC: u32 = 55;
+
+/// Lets Music Player Daemon clients, like ncmpcpp, MPD phone apps, and mpc scripts, control the
+/// player. Speaks enough of the MPD protocol for browsing, queueing, and playback controls.
+/// Paths are given to clients relative to the music directory.
+pub struct MpdServer {
+    _thread: JoinHandle<()>,
+}
+
+/// A command that failed, sent back to the client as an ACK line
+struct Ack {
+    code: u32,
+    message: String,
+}
+
+impl Ack {
+    fn new(code: u32, message: impl Into<String>) -> Ack {
+        Ack {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// What the connection does after a command
+enum Next {
+    Continue,
+    // Wait for one of the specified subsystems to change. Empty means any of them.
+    Idle(Vec<String>),
+    Close,
+}
+
+/// One connected client
+struct MpdClient {
+    ctrl: Controller,
+    music_root: PathBuf,
+    stream: TcpStream,
+    // Lines read from the client by a separate thread, so we can wait on them and the controller's
+    // events at the same time
+    lines: Receiver<String>,
+    events: Subscription<PlayerEvent>,
+    // Subsystems that have changed since the client last heard about them from idle
+    changed: BTreeSet<&'static str>,
+    // To tell whether a ModeChanged event was a volume change or an options change
+    modes: Modes,
+    // The commands gathered between command_list_begin and command_list_end. The flag is true if
+    // each command's success is reported with list_OK.
+    command_list: Option<(Vec<Vec<String>>, bool)>,
+}
+
+impl MpdServer {
+    /// Starts listening for MPD clients on the specified address
+    /// # Parameters
+    /// music_root = The directory clients see as the top of the music database
+    pub fn init(ctrl: Controller, address: SocketAddr, music_root: PathBuf) -> io::Result<MpdServer> {
+        let listener = TcpListener::bind(address)?;
+        println!("MPD server listening on {}", address);
+        let _thread = std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("Failed to accept MPD client: {}", e);
+                        continue;
+                    }
+                };
+                let ctrl = ctrl.clone();
+                let music_root = music_root.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = MpdClient::init(ctrl, music_root, stream).and_then(|mut client| client.run()) {
+                        eprintln!("MPD client connection failed: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(MpdServer {
+            _thread,
+        })
+    }
+}
+
+impl MpdClient {
+    fn init(mut ctrl: Controller, music_root: PathBuf, stream: TcpStream) -> io::Result<MpdClient> {
+        let (line_sender, lines) = unbounded::<String>();
+        let reader = BufReader::new(stream.try_clone()?);
+        std::thread::spawn(move || {
+            // Ends when the client hangs up, which disconnects the channel and ends the connection
+            for line in reader.lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if line_sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let events = ctrl.subscribe();
+        let modes = ctrl.snapshot().modes();
+        Ok(MpdClient {
+            ctrl,
+            music_root,
+            stream,
+            lines,
+            events,
+            changed: BTreeSet::new(),
+            modes,
+            command_list: None,
+        })
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        self.stream.write_all(format!("OK MPD {}\n", PROTOCOL_VERSION).as_bytes())?;
+        let mut idle: Option<Vec<String>> = None;
+        loop {
+            select! {
+                recv(self.events.receiver()) -> player_event => {
+                    if let Ok(player_event) = player_event {
+                        self.note_change(player_event);
+                    }
+                }
+                recv(self.lines) -> line => {
+                    let Ok(line) = line else {
+                        return Ok(());
+                    };
+                    if idle.is_some() {
+                        // The only thing a client may send while idle is noidle, which ends the
+                        // wait early with whatever has changed so far
+                        if line.trim() != "noidle" {
+                            return Ok(());
+                        }
+                        let subsystems = idle.take().unwrap_or_default();
+                        let response = self.take_changes(&subsystems);
+                        self.stream.write_all(format!("{}OK\n", response).as_bytes())?;
+                        continue;
+                    }
+                    match self.handle_line(&line)? {
+                        Next::Continue => {}
+                        Next::Idle(subsystems) => idle = Some(subsystems),
+                        Next::Close => return Ok(()),
+                    }
+                }
+            }
+
+            if let Some(subsystems) = &idle {
+                let response = self.take_changes(subsystems);
+                if !response.is_empty() {
+                    self.stream.write_all(format!("{}OK\n", response).as_bytes())?;
+                    idle = None;
+                }
+            }
+        }
+    }
+
+    /// Works out which subsystem a controller event belongs to, for idle. Only the player, mixer,
+    /// options, and playlist subsystems ever change.
+    fn note_change(&mut self, player_event: PlayerEvent) {
+        match player_event {
+            PlayerEvent::TrackStarted(..) | PlayerEvent::TrackEnded(_) | PlayerEvent::StateChanged(_) => {
+                self.changed.insert("player");
+            }
+            PlayerEvent::ModeChanged(modes) => {
+                if modes.volume != self.modes.volume || modes.muted != self.modes.muted {
+                    self.changed.insert("mixer");
+                }
+                if modes.random != self.modes.random || modes.repeat != self.modes.repeat {
+                    self.changed.insert("options");
+                }
+                self.modes = modes;
+            }
+            PlayerEvent::QueueChanged(..) => {
+                self.changed.insert("playlist");
+            }
+            PlayerEvent::PositionChanged(..) | PlayerEvent::DirChanged(..) | PlayerEvent::Error(_) => {}
+            // We can't tell what was missed, so let the client fetch everything again
+            PlayerEvent::Resync => {
+                self.modes = self.ctrl.snapshot().modes();
+                self.changed.extend(["player", "mixer", "options", "playlist"]);
+            }
+        }
+    }
+
+    /// Takes the changes to the specified subsystems, formatted as idle's response. Empty
+    /// subsystems means all of them.
+    fn take_changes(&mut self, subsystems: &[String]) -> String {
+        let mut response = String::new();
+        let wanted = |subsystem: &str| subsystems.is_empty() || subsystems.iter().any(|wanted| wanted == subsystem);
+        for subsystem in self.changed.iter().filter(|subsystem| wanted(subsystem)) {
+            let _ = writeln!(response, "changed: {}", subsystem);
+        }
+        self.changed.retain(|subsystem| !wanted(subsystem));
+        response
+    }
+
+    /// Handles one line from the client, writing back the response
+    fn handle_line(&mut self, line: &str) -> io::Result<Next> {
+        let args = match split_args(line) {
+            Ok(args) => args,
+            Err(e) => {
+                self.command_list = None;
+                return self.send_ack(&Ack::new(ACK_ERROR_ARG, e), 0, "");
+            }
+        };
+        let Some(command) = args.first().map(String::as_str) else {
+            return self.send_ack(&Ack::new(ACK_ERROR_UNKNOWN, "No command given"), 0, "");
+        };
+
+        // Gather up a command list, running it all once it ends
+        if let Some((commands, _)) = &mut self.command_list {
+            if command != "command_list_end" {
+                commands.push(args);
+                return Ok(Next::Continue);
+            }
+            let (commands, list_ok) = self.command_list.take().unwrap_or_default();
+            let mut response = String::new();
+            for (index, args) in commands.iter().enumerate() {
+                match self.execute(args) {
+                    Ok(output) => {
+                        response.push_str(&output);
+                        if list_ok {
+                            response.push_str("list_OK\n");
+                        }
+                    }
+                    Err(ack) => {
+                        self.stream.write_all(response.as_bytes())?;
+                        return self.send_ack(&ack, index, &args[0]);
+                    }
+                }
+            }
+            self.stream.write_all(format!("{}OK\n", response).as_bytes())?;
+            return Ok(Next::Continue);
+        }
+
+        match command {
+            "command_list_begin" => self.command_list = Some((Vec::new(), false)),
+            "command_list_ok_begin" => self.command_list = Some((Vec::new(), true)),
+            "idle" => return Ok(Next::Idle(args[1..].to_vec())),
+            "close" => return Ok(Next::Close),
+            _ => {
+                match self.execute(&args) {
+                    Ok(output) => self.stream.write_all(format!("{}OK\n", output).as_bytes())?,
+                    Err(ack) => return self.send_ack(&ack, 0, command),
+                }
+            }
+        }
+        Ok(Next::Continue)
+    }
+
+    fn send_ack(&mut self, ack: &Ack, list_index: usize, command: &str) -> io::Result<Next> {
+        self.stream.write_all(format!("ACK [{}@{}] {{{}}} {}\n", ack.code, list_index, command, ack.message).as_bytes())?;
+        Ok(Next::Continue)
+    }
+
+    /// Runs a single command, returning its output
+    fn execute(&mut self, args: &[String]) -> Result<String, Ack> {
+        let command = args[0].as_str();
+        let arg = args.get(1).map(String::as_str);
+        let player_error = |e: crate::music_player::PlayerError| Ack::new(ACK_ERROR_SYSTEM, e.to_string());
+        let no_song = |e: io::Error| Ack::new(ACK_ERROR_ARG, e.to_string());
+        let mut output = String::new();
+        match command {
+            "ping" => {}
+            "commands" => {
+                for command in COMMANDS {
+                    let _ = writeln!(output, "command: {}", command);
+                }
+            }
+            "notcommands" => {}
+            // We always send every tag we have, so there's nothing to set up
+            "tagtypes" => {
+                if arg.is_none() {
+                    for tag in ["Artist", "Album", "Title", "Track", "Disc", "Date", "Genre"] {
+                        let _ = writeln!(output, "tagtype: {}", tag);
+                    }
+                }
+            }
+            "status" => output = self.status(),
+            "currentsong" => {
+                if let Some((song, index, id)) = self.ctrl.current_queued() {
+                    // The song may have changed since, in which case its tags come from the library
+                    let settings = self.ctrl.snapshot();
+                    let tags = match settings.now_playing.filter(|_| Path::new(&settings.song_playing) == song) {
+                        Some(tags) => Some(tags),
+                        None => self.ctrl.tags(std::slice::from_ref(&song)).into_iter().next().flatten(),
+                    };
+                    output = self.song_info(&song, tags.as_ref(), Some((index, id)));
+                }
+            }
+            "play" => match arg {
+                Some(index) => self.ctrl.play_queued(parse_number(index)?).map_err(player_error)?,
+                None => self.ctrl.play().map_err(player_error)?,
+            },
+            "playid" => match arg {
+                Some(id) => {
+                    let index = self.queue_index_of(id)?;
+                    self.ctrl.play_queued(index).map_err(player_error)?
+                }
+                None => self.ctrl.play().map_err(player_error)?,
+            },
+            "pause" => match arg.map(parse_bool).transpose()? {
+                Some(true) => self.ctrl.pause(),
+                Some(false) => self.ctrl.play().map_err(player_error)?,
+                None => self.ctrl.play_pause().map_err(player_error)?,
+            },
+            "stop" => self.ctrl.stop(),
+            "next" => self.ctrl.next().map_err(player_error)?,
+            "previous" => self.ctrl.prior_track().map_err(player_error)?,
+            "seekcur" => {
+                let time = arg.ok_or_else(|| Ack::new(ACK_ERROR_ARG, "Missing time"))?;
+                let secs: f64 = time.parse().ok()
+                    .filter(|secs: &f64| secs.is_finite())
+                    .ok_or_else(|| Ack::new(ACK_ERROR_ARG, format!("Not a time: {}", time)))?;
+                let res = if time.starts_with('+') || time.starts_with('-') {
+                    self.ctrl.seek_by(secs)
+                } else {
+                    let position = Duration::try_from_secs_f64(secs.max(0.0))
+                        .map_err(|_| Ack::new(ACK_ERROR_ARG, format!("Time out of range: {}", time)))?;
+                    self.ctrl.seek(position)
+                };
+                res.map_err(|e| match e {
+                    crate::music_player::PlayerError::NothingPlaying => Ack::new(ACK_ERROR_PLAYER_SYNC, "Not playing"),
+                    e => player_error(e),
+                })?;
+            }
+            "setvol" => {
+                let volume: u32 = parse_number(arg.unwrap_or_default())?;
+                if volume > 100 {
+                    return Err(Ack::new(ACK_ERROR_ARG, "Volume must be 0 to 100"));
+                }
+                self.ctrl.set_volume(volume as f32 / 100.0);
+            }
+            "volume" => {
+                let change: i32 = parse_number(arg.unwrap_or_default())?;
+                let volume = self.ctrl.snapshot().volume + change as f32 / 100.0;
+                self.ctrl.set_volume(volume);
+            }
+            "random" => self.ctrl.set_random(parse_bool(arg.unwrap_or_default())?),
+            // Our repeat modes map onto MPD's repeat and single flags. Single without repeat,
+            // which stops after the current song, has no equivalent so it repeats the song.
+            "repeat" => {
+                let repeat = match (parse_bool(arg.unwrap_or_default())?, self.ctrl.snapshot().repeat) {
+                    (false, _) => RepeatMode::Off,
+                    (true, RepeatMode::One) => RepeatMode::One,
+                    (true, _) => RepeatMode::All,
+                };
+                self.ctrl.set_repeat_mode(repeat);
+            }
+            "single" => {
+                let repeat = match (parse_bool(arg.unwrap_or_default())?, self.ctrl.snapshot().repeat) {
+                    (true, _) => RepeatMode::One,
+                    (false, RepeatMode::One) => RepeatMode::All,
+                    (false, repeat) => repeat,
+                };
+                self.ctrl.set_repeat_mode(repeat);
+            }
+            "lsinfo" => output = self.lsinfo(arg.unwrap_or_default())?,
+            "add" => {
+                let path = self.resolve(arg.ok_or_else(|| Ack::new(ACK_ERROR_ARG, "Missing URI"))?)?;
+                self.ctrl.enqueue(&path.to_string_lossy())
+                    .map_err(|e| Ack::new(ACK_ERROR_NO_EXIST, e.to_string()))?;
+            }
+            "clear" => self.ctrl.clear_queue(),
+            "delete" => {
+                let queue_len = self.ctrl.get_queue().0.len();
+                let range = parse_range(arg.ok_or_else(|| Ack::new(ACK_ERROR_ARG, "Missing song position"))?, queue_len)?;
+                // From the end, so removing a song doesn't shift the ones still to go
+                for index in range.rev() {
+                    self.ctrl.remove_queued(index).map_err(no_song)?;
+                }
+            }
+            "deleteid" => {
+                let index = self.queue_index_of(arg.unwrap_or_default())?;
+                self.ctrl.remove_queued(index).map_err(no_song)?;
+            }
+            "move" => {
+                let queue_len = self.ctrl.get_queue().0.len();
+                let range = parse_range(arg.ok_or_else(|| Ack::new(ACK_ERROR_ARG, "Missing song position"))?, queue_len)?;
+                let to: usize = parse_number(args.get(2).map(String::as_str).unwrap_or_default())?;
+                if to + range.len() > queue_len {
+                    return Err(Ack::new(ACK_ERROR_ARG, "Bad song index"));
+                }
+                // Move the songs one at a time so they end up together, in the same order, with
+                // the first at the new position
+                for offset in 0..range.len() {
+                    let res = if to > range.start {
+                        self.ctrl.move_queued(range.start, to + range.len() - 1)
+                    } else {
+                        self.ctrl.move_queued(range.start + offset, to + offset)
+                    };
+                    res.map_err(no_song)?;
+                }
+            }
+            "moveid" => {
+                let from = self.queue_index_of(arg.unwrap_or_default())?;
+                let to: usize = parse_number(args.get(2).map(String::as_str).unwrap_or_default())?;
+                self.ctrl.move_queued(from, to).map_err(no_song)?;
+            }
+            "playlistinfo" => output = self.playlistinfo(arg)?,
+            "plchanges" => {
+                let version: u64 = parse_number(arg.unwrap_or_default())?;
+                let (queue, _) = self.ctrl.get_queue();
+                let ids = self.ctrl.get_queue_ids();
+                let changes = self.ctrl.queue_changes_since(version);
+                let songs: Vec<PathBuf> = changes.iter().filter_map(|&index| queue.get(index).cloned()).collect();
+                let tags = self.ctrl.tags(&songs);
+                for ((index, song), tags) in changes.into_iter().zip(&songs).zip(tags) {
+                    output.push_str(&self.song_info(song, tags.as_ref(), ids.get(index).map(|&id| (index, id))));
+                }
+            }
+            _ => return Err(Ack::new(ACK_ERROR_UNKNOWN, format!("unknown command \"{}\"", command))),
+        }
+        Ok(output)
+    }
+
+    fn status(&self) -> String {
+        let settings = self.ctrl.snapshot();
+        let cur_song = self.ctrl.current_queued();
+        let mut output = String::new();
+        let volume = if settings.muted { 0 } else { (settings.volume * 100.0).round() as u32 };
+        let _ = writeln!(output, "volume: {}", volume);
+        let _ = writeln!(output, "repeat: {}", (settings.repeat != RepeatMode::Off) as u8);
+        let _ = writeln!(output, "random: {}", settings.random as u8);
+        let _ = writeln!(output, "single: {}", (settings.repeat == RepeatMode::One) as u8);
+        let _ = writeln!(output, "consume: 0");
+        let _ = writeln!(output, "playlist: {}", settings.queue_version);
+        let _ = writeln!(output, "playlistlength: {}", settings.queue_len);
+        let state = match settings.state {
+            PlaybackState::Playing => "play",
+            PlaybackState::Paused => "pause",
+            PlaybackState::Stopped => "stop",
+        };
+        let _ = writeln!(output, "state: {}", state);
+        if let Some((_, index, id)) = cur_song {
+            let _ = writeln!(output, "song: {}", index);
+            let _ = writeln!(output, "songid: {}", id);
+        }
+        if settings.state != PlaybackState::Stopped {
+            let (elapsed, total) = settings.song_time;
+            let _ = writeln!(output, "time: {}:{}", elapsed, total.unwrap_or(0));
+            let _ = writeln!(output, "elapsed: {}", elapsed);
+            if let Some(total) = total {
+                let _ = writeln!(output, "duration: {}", total);
+            }
+        }
+        output
+    }
+
+    /// Lists the sub directories, music files, and playlists in a directory of the music database
+    fn lsinfo(&self, uri: &str) -> Result<String, Ack> {
+        let path = self.resolve(uri)?;
+        if path.is_file() {
+            let tags = self.ctrl.tags(std::slice::from_ref(&path)).pop().flatten();
+            return Ok(self.song_info(&path, tags.as_ref(), None));
+        }
+
+        let dir = path.to_string_lossy().to_string();
+        let no_dir = |e: io::Error| Ack::new(ACK_ERROR_NO_EXIST, format!("{}: {}", uri, e));
+        let mut sub_dirs = file_utils::sub_directories(&dir).map_err(no_dir)?;
+        sub_dirs.sort();
+        let files = self.ctrl.music_files(&dir).map_err(no_dir)?;
+        let tags = self.ctrl.tags(&files);
+        let playlists = file_utils::playlist_files(&dir).map_err(no_dir)?;
+
+        let mut output = String::new();
+        for sub_dir in sub_dirs {
+            let _ = writeln!(output, "directory: {}", self.uri(&path.join(sub_dir)));
+        }
+        for (file, tags) in files.iter().zip(tags) {
+            output.push_str(&self.song_info(file, tags.as_ref(), None));
+        }
+        for playlist in playlists {
+            let _ = writeln!(output, "playlist: {}", self.uri(&path.join(playlist)));
+        }
+        Ok(output)
+    }
+
+    /// Lists the songs in the queue, or just the one at the specified position or range of
+    /// positions, like "3" or "3:7"
+    fn playlistinfo(&self, positions: Option<&str>) -> Result<String, Ack> {
+        let (queue, _) = self.ctrl.get_queue();
+        let range = match positions {
+            None => 0..queue.len(),
+            Some(positions) => parse_range(positions, queue.len())?,
+        };
+        let songs = queue.get(range.clone()).unwrap_or_default();
+        let tags = self.ctrl.tags(songs);
+        let ids = self.ctrl.get_queue_ids();
+        let mut output = String::new();
+        for ((index, song), tags) in range.zip(songs).zip(tags) {
+            output.push_str(&self.song_info(song, tags.as_ref(), ids.get(index).map(|&id| (index, id))));
+        }
+        Ok(output)
+    }
+
+    /// Describes a song the way MPD does, with its position in the queue and id if it is queued
+    fn song_info(&self, song: &Path, tags: Option<&TrackMetadata>, queued: Option<(usize, u64)>) -> String {
+        let mut output = String::new();
+        let _ = writeln!(output, "file: {}", self.uri(song));
+        if let Some(tags) = tags {
+            let tag_values = [
+                ("Artist", tags.artist.clone()),
+                ("Album", tags.album.clone()),
+                ("Title", tags.title.clone()),
+                ("Track", tags.track_number.map(|number| number.to_string())),
+                ("Disc", tags.disc_number.map(|number| number.to_string())),
+                ("Date", tags.year.map(|year| year.to_string())),
+                ("Genre", tags.genre.clone()),
+            ];
+            for (name, value) in tag_values {
+                if let Some(value) = value {
+                    let _ = writeln!(output, "{}: {}", name, value);
+                }
+            }
+            if let Some(duration) = tags.duration {
+                let _ = writeln!(output, "Time: {}", duration);
+                let _ = writeln!(output, "duration: {}", duration);
+            }
+        }
+        if let Some((index, id)) = queued {
+            let _ = writeln!(output, "Pos: {}", index);
+            let _ = writeln!(output, "Id: {}", id);
+        }
+        output
+    }
+
+    /// Finds the position in the queue of the song with the specified id
+    fn queue_index_of(&self, id: &str) -> Result<usize, Ack> {
+        self.ctrl.queue_index_of(parse_number(id)?)
+            .ok_or_else(|| Ack::new(ACK_ERROR_NO_EXIST, format!("No such song id: {}", id)))
+    }
+
+    /// Turns a URI from the client, which is relative to the music directory, into a path. URIs
+    /// can't reach outside the music directory.
+    fn resolve(&self, uri: &str) -> Result<PathBuf, Ack> {
+        let relative = Path::new(uri.trim_matches('/'));
+        if relative.components().any(|component| !matches!(component, Component::Normal(_))) {
+            return Err(Ack::new(ACK_ERROR_NO_EXIST, format!("No such directory or file: {}", uri)));
+        }
+        let path = self.music_root.join(relative);
+        if !path.exists() {
+            return Err(Ack::new(ACK_ERROR_NO_EXIST, format!("No such directory or file: {}", uri)));
+        }
+        Ok(path)
+    }
+
+    /// Turns a path into the URI the client sees. Songs outside the music directory, which can be
+    /// queued from playlists, keep their full path.
+    fn uri(&self, path: &Path) -> String {
+        path.strip_prefix(&self.music_root).unwrap_or(path).to_string_lossy().to_string()
+    }
+}
+
+impl Drop for MpdClient {
+    fn drop(&mut self) {
+        // Wakes up the thread reading from the client so it exits too
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// Splits a command line into its command and arguments. Arguments with spaces in them are
+/// wrapped in double quotes, with a backslash escaping any quote or backslash inside them.
+fn split_args(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut arg = String::new();
+        if c == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => arg.push(chars.next().ok_or("Unterminated quote")?),
+                    Some(c) => arg.push(c),
+                    None => return Err("Unterminated quote".to_string()),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                arg.push(c);
+                chars.next();
+            }
+        }
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+fn parse_number<T: std::str::FromStr>(arg: &str) -> Result<T, Ack> {
+    arg.parse().map_err(|_| Ack::new(ACK_ERROR_ARG, format!("Integer expected: {}", arg)))
+}
+
+/// Parses a queue position, like "3", or a range of positions, like "3:7" or "3:", into the range
+/// of positions it covers. The end of a range is cut short at the end of the queue.
+fn parse_range(positions: &str, queue_len: usize) -> Result<Range<usize>, Ack> {
+    let range = match positions.split_once(':') {
+        Some((start, "")) => parse_number(start)?..queue_len,
+        Some((start, end)) => parse_number(start)?..parse_number::<usize>(end)?.min(queue_len),
+        None => {
+            let index: usize = parse_number(positions)?;
+            index..index + 1
+        }
+    };
+    if range.start > range.end || range.end > queue_len {
+        return Err(Ack::new(ACK_ERROR_ARG, "Bad song index"));
+    }
+    Ok(range)
+}
+
+fn parse_bool(arg: &str) -> Result<bool, Ack> {
+    match arg {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        _ => Err(Ack::new(ACK_ERROR_ARG, format!("Boolean (0/1) expected: {}", arg))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_args_on_whitespace() {
+        assert_eq!(split_args("  play\t3 \r\n").unwrap(), ["play", "3"]);
+        assert!(split_args("   ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn quoted_args_keep_spaces() {
+        assert_eq!(split_args("add \"Some Band/01 - Intro.mp3\"").unwrap(), ["add", "Some Band/01 - Intro.mp3"]);
+        assert_eq!(split_args("find \"\" x").unwrap(), ["find", "", "x"]);
+    }
+
+    #[test]
+    fn backslash_escapes_inside_quotes() {
+        assert_eq!(split_args(r#"add "say \"hi\" C:\\music""#).unwrap(), ["add", r#"say "hi" C:\music"#]);
+        // Outside quotes a backslash is just a character
+        assert_eq!(split_args(r"add a\b").unwrap(), ["add", r"a\b"]);
+    }
+
+    #[test]
+    fn unterminated_quotes_are_errors() {
+        assert_eq!(split_args("add \"open").unwrap_err(), "Unterminated quote");
+        assert_eq!(split_args("add \"open\\").unwrap_err(), "Unterminated quote");
+        assert_eq!(split_args("add \"open\\\"").unwrap_err(), "Unterminated quote");
+    }
+}
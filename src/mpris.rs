@@ -0,0 +1,603 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use zbus::blocking::connection;
+use zbus::blocking::Connection;
+use zbus::fdo;
+use zbus::interface;
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::{ObjectPath, Value};
+use crate::controller::Controller;
+use crate::event_bus::Subscription;
+use crate::metadata::metadata::TrackMetadata;
+use crate::player_event::{PlaybackState, PlayerEvent};
+use crate::settings_changed::RepeatMode;
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.funoform_mp3_dir_player";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+// The track id MPRIS reserves for when there is no current track
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+const MICROS_PER_SEC: i64 = 1_000_000;
+
+/// Lets desktop media keys, media widgets, and tools like playerctl control the player, through
+/// the MPRIS interfaces on the D-Bus session bus. The session bus is found through
+/// $DBUS_SESSION_BUS_ADDRESS, so pointing that at a private dbus-daemon keeps it off the desktop's.
+pub struct Mpris {
+    _connection: Connection,
+    _thread: JoinHandle<()>,
+}
+
+/// The property values last sent out in PropertiesChanged, so each change is only announced once.
+/// Setters called over D-Bus announce their own change, so they record it here too.
+type Announced = Arc<Mutex<HashMap<&'static str, Value<'static>>>>;
+
+/// The second Seek or SetPosition last jumped to. They send their own Seeked signal, so the
+/// position update the controller sends for the jump shouldn't be announced again.
+type SeekedTo = Arc<Mutex<Option<u32>>>;
+
+/// The org.mpris.MediaPlayer2 interface, which describes the application
+struct MediaPlayer2;
+
+/// The org.mpris.MediaPlayer2.Player interface, which controls playback
+struct Player {
+    ctrl: Controller,
+    announced: Announced,
+    seeked_to: SeekedTo,
+}
+
+/// Where a Seek lands
+#[derive(Debug, PartialEq)]
+enum SeekTarget {
+    /// Microseconds into the current song
+    Position(i64),
+    /// Past the end of the song, which goes on to the next one
+    Next,
+}
+
+impl Mpris {
+    pub fn init(mut ctrl: Controller) -> zbus::Result<Mpris> {
+        // Subscribe before going on the bus so no change goes unannounced
+        let events = ctrl.subscribe();
+        let settings = ctrl.snapshot();
+        let announced: Announced = Arc::new(Mutex::new(HashMap::from([
+            ("PlaybackStatus", Value::from(playback_status(settings.state))),
+            ("LoopStatus", Value::from(loop_status(settings.repeat))),
+            ("Shuffle", Value::from(settings.random)),
+            ("Volume", Value::from(volume(settings.volume, settings.muted))),
+        ])));
+        let seeked_to: SeekedTo = Arc::new(Mutex::new(None));
+        let player = Player {
+            ctrl: ctrl.clone(),
+            announced: Arc::clone(&announced),
+            seeked_to: Arc::clone(&seeked_to),
+        };
+        let _connection = connection::Builder::session()?
+            .serve_at(OBJECT_PATH, MediaPlayer2)?
+            .serve_at(OBJECT_PATH, player)?
+            .build()?;
+
+        // The spec lets a second copy of the player tell itself apart with an instance suffix
+        if _connection.request_name(BUS_NAME).is_err() {
+            _connection.request_name(format!("{}.instance{}", BUS_NAME, std::process::id()))?;
+        }
+
+        let _thread = std::thread::spawn({
+            let connection = _connection.clone();
+            move || announce_changes(&connection, &ctrl, &events, &announced, &seeked_to)
+        });
+
+        Ok(Mpris {
+            _connection,
+            _thread,
+        })
+    }
+}
+
+/// Sends PropertiesChanged and Seeked signals as the controller's events come in
+fn announce_changes(connection: &Connection, ctrl: &Controller, events: &Subscription<PlayerEvent>, announced: &Announced,
+                    seeked_to: &SeekedTo) {
+    // Progress updates come once a second, so anything else is the position jumping
+    let mut last_position: Option<u32> = None;
+    for player_event in events.receiver().iter() {
+        let mut changed: HashMap<&'static str, Value<'static>> = HashMap::new();
+        match player_event {
+            PlayerEvent::TrackStarted(song, tags) => {
+                changed.insert("Metadata", Value::from(metadata(Some((&song, tags.as_ref())))));
+                last_position = None;
+            }
+            PlayerEvent::StateChanged(state) => {
+                changed.insert("PlaybackStatus", Value::from(playback_status(state)));
+                // A stopped player has no current track
+                if state == PlaybackState::Stopped {
+                    changed.insert("Metadata", Value::from(metadata(None)));
+                }
+            }
+            PlayerEvent::ModeChanged(modes) => {
+                changed.insert("Shuffle", Value::from(modes.random));
+                changed.insert("LoopStatus", Value::from(loop_status(modes.repeat)));
+                changed.insert("Volume", Value::from(volume(modes.volume, modes.muted)));
+            }
+            PlayerEvent::PositionChanged(elapsed, _) => {
+                let jumped = last_position.is_some_and(|last| elapsed < last || elapsed > last + 2);
+                last_position = Some(elapsed);
+                let seeked_here = seeked_to.lock().unwrap().take_if(|position| *position == elapsed).is_some();
+                if jumped && !seeked_here {
+                    let position = elapsed as i64 * MICROS_PER_SEC;
+                    if let Err(e) = connection.emit_signal(None::<&str>, OBJECT_PATH, PLAYER_INTERFACE, "Seeked", &(position,)) {
+                        eprintln!("Failed to send MPRIS Seeked signal: {}", e);
+                    }
+                }
+            }
+            PlayerEvent::TrackEnded(_) | PlayerEvent::QueueChanged(..) | PlayerEvent::DirChanged(..) | PlayerEvent::Error(_) => {}
+            // Announce anything that changed while we weren't keeping up
+            PlayerEvent::Resync => {
+                let settings = ctrl.snapshot();
+                let song = Some(Path::new(&settings.song_playing)).filter(|_| !settings.song_playing.is_empty());
+                changed.insert("Metadata", Value::from(metadata(song.map(|song| (song, settings.now_playing.as_ref())))));
+                changed.insert("PlaybackStatus", Value::from(playback_status(settings.state)));
+                changed.insert("Shuffle", Value::from(settings.random));
+                changed.insert("LoopStatus", Value::from(loop_status(settings.repeat)));
+                changed.insert("Volume", Value::from(volume(settings.volume, settings.muted)));
+                last_position = None;
+            }
+        }
+
+        let mut announced = announced.lock().unwrap();
+        changed.retain(|name, value| announced.get(name) != Some(value));
+        announced.extend(changed.iter().map(|(name, value)| (*name, value.clone())));
+        drop(announced);
+
+        if !changed.is_empty() {
+            let invalidated: Vec<&str> = Vec::new();
+            let body = (PLAYER_INTERFACE, changed, invalidated);
+            if let Err(e) = connection.emit_signal(None::<&str>, OBJECT_PATH, "org.freedesktop.DBus.Properties",
+                                                   "PropertiesChanged", &body) {
+                eprintln!("Failed to send MPRIS PropertiesChanged signal: {}", e);
+            }
+        }
+    }
+}
+
+fn playback_status(state: PlaybackState) -> &'static str {
+    match state {
+        PlaybackState::Playing => "Playing",
+        PlaybackState::Paused => "Paused",
+        PlaybackState::Stopped => "Stopped",
+    }
+}
+
+fn loop_status(repeat: RepeatMode) -> &'static str {
+    match repeat {
+        RepeatMode::Off => "None",
+        RepeatMode::One => "Track",
+        RepeatMode::All => "Playlist",
+    }
+}
+
+/// MPRIS has no mute, so muted shows as silent
+fn volume(volume: f32, muted: bool) -> f64 {
+    if muted {
+        0.0
+    } else {
+        volume as f64
+    }
+}
+
+/// Works out where seeking the specified microseconds from the current position lands. Going back
+/// past the start goes to the start.
+fn seek_target(song_time: (u32, Option<u32>), offset: i64) -> SeekTarget {
+    let (elapsed, total) = song_time;
+    let position = (elapsed as i64 * MICROS_PER_SEC).saturating_add(offset).max(0);
+    match total {
+        Some(total) if position >= total as i64 * MICROS_PER_SEC => SeekTarget::Next,
+        _ => SeekTarget::Position(position),
+    }
+}
+
+/// Gets an id for a song that stays the same for as long as it is playing
+fn track_id(song: &Path) -> ObjectPath<'static> {
+    let mut hasher = DefaultHasher::new();
+    song.hash(&mut hasher);
+    ObjectPath::try_from(format!("/org/funoform/mp3_dir_player/track/{:016x}", hasher.finish()))
+        .expect("Track ids are valid object paths")
+}
+
+/// Builds the Metadata property for a song, or for no song at all
+fn metadata(song: Option<(&Path, Option<&TrackMetadata>)>) -> HashMap<String, Value<'static>> {
+    let mut map: HashMap<String, Value<'static>> = HashMap::new();
+    let Some((song, tags)) = song else {
+        map.insert("mpris:trackid".to_string(), Value::from(ObjectPath::from_static_str_unchecked(NO_TRACK)));
+        return map;
+    };
+
+    map.insert("mpris:trackid".to_string(), Value::from(track_id(song)));
+    map.insert("xesam:url".to_string(), Value::from(file_url(song)));
+    let file_name = song.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let title = tags.and_then(|tags| tags.title.clone()).unwrap_or(file_name);
+    map.insert("xesam:title".to_string(), Value::from(title));
+    let Some(tags) = tags else {
+        return map;
+    };
+    if let Some(duration) = tags.duration {
+        map.insert("mpris:length".to_string(), Value::from(duration as i64 * MICROS_PER_SEC));
+    }
+    if let Some(artist) = &tags.artist {
+        map.insert("xesam:artist".to_string(), Value::from(vec![artist.clone()]));
+    }
+    if let Some(album) = &tags.album {
+        map.insert("xesam:album".to_string(), Value::from(album.clone()));
+    }
+    if let Some(genre) = &tags.genre {
+        map.insert("xesam:genre".to_string(), Value::from(vec![genre.clone()]));
+    }
+    if let Some(track_number) = tags.track_number {
+        map.insert("xesam:trackNumber".to_string(), Value::from(track_number as i32));
+    }
+    if let Some(disc_number) = tags.disc_number {
+        map.insert("xesam:discNumber".to_string(), Value::from(disc_number as i32));
+    }
+    if let Some(year) = tags.year {
+        map.insert("xesam:contentCreated".to_string(), Value::from(format!("{:04}", year)));
+    }
+    map
+}
+
+/// Turns a path into a file:// URL, escaping anything that isn't allowed in one
+fn file_url(path: &Path) -> String {
+    let mut url = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => url.push(byte as char),
+            _ => url.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    url
+}
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl MediaPlayer2 {
+    /// We have no window to bring forward
+    fn raise(&self) {}
+
+    /// Quitting is left to the terminal UI
+    fn quit(&self) {}
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> &str {
+        "Fun-O-Form Music Dir Player"
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+impl Player {
+    /// Notes a value zbus is going to announce after one of our setters, so it isn't sent twice.
+    /// Called before the change is made, as the controller's event for it may be handled first.
+    fn record(&self, name: &'static str, value: Value<'static>) {
+        self.announced.lock().unwrap().insert(name, value);
+    }
+
+    /// Jumps to the specified number of microseconds into the current song and sends Seeked
+    async fn seek_to(&mut self, position: i64, emitter: &SignalEmitter<'_>) {
+        *self.seeked_to.lock().unwrap() = Some((position / MICROS_PER_SEC) as u32);
+        if let Err(e) = self.ctrl.seek(Duration::from_micros(position as u64)) {
+            // The song ended between checking and seeking, so there's nothing to announce
+            eprintln!("Unable to seek: {}", e);
+            *self.seeked_to.lock().unwrap() = None;
+            return;
+        }
+        if let Err(e) = Self::seeked(emitter, position).await {
+            eprintln!("Failed to send MPRIS Seeked signal: {}", e);
+        }
+    }
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    fn next(&mut self) -> fdo::Result<()> {
+        self.ctrl.next().map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    fn previous(&mut self) -> fdo::Result<()> {
+        self.ctrl.prior_track().map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    fn pause(&mut self) {
+        self.ctrl.pause();
+    }
+
+    fn play_pause(&mut self) -> fdo::Result<()> {
+        self.ctrl.play_pause().map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    fn stop(&mut self) {
+        self.ctrl.stop();
+    }
+
+    fn play(&mut self) -> fdo::Result<()> {
+        self.ctrl.play().map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Jumps forward (positive) or backward (negative) the specified number of microseconds.
+    /// Going back past the start goes to the start, and going past the end goes to the next song.
+    async fn seek(&mut self, offset: i64, #[zbus(signal_emitter)] emitter: SignalEmitter<'_>) -> fdo::Result<()> {
+        let settings = self.ctrl.snapshot();
+        if settings.song_playing.is_empty() {
+            return Ok(());
+        }
+        match seek_target(settings.song_time, offset) {
+            SeekTarget::Position(position) => {
+                self.seek_to(position, &emitter).await;
+                Ok(())
+            }
+            SeekTarget::Next => self.next(),
+        }
+    }
+
+    /// Jumps to the specified number of microseconds into the song, as long as it is still the
+    /// song the caller thinks it is and the position is within it
+    async fn set_position(&mut self, track_id: ObjectPath<'_>, position: i64,
+                          #[zbus(signal_emitter)] emitter: SignalEmitter<'_>) {
+        let settings = self.ctrl.snapshot();
+        let song = settings.song_playing;
+        let past_end = settings.song_time.1.is_some_and(|total| position > total as i64 * MICROS_PER_SEC);
+        if position < 0 || past_end || song.is_empty() || track_id != self::track_id(Path::new(&song)) {
+            return;
+        }
+        self.seek_to(position, &emitter).await;
+    }
+
+    fn open_uri(&self, _uri: &str) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported("Opening URIs is not supported".to_string()))
+    }
+
+    #[zbus(signal)]
+    async fn seeked(emitter: &SignalEmitter<'_>, position: i64) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn playback_status(&self) -> &str {
+        playback_status(self.ctrl.snapshot().state)
+    }
+
+    #[zbus(property)]
+    fn loop_status(&self) -> &str {
+        loop_status(self.ctrl.snapshot().repeat)
+    }
+
+    #[zbus(property)]
+    fn set_loop_status(&mut self, status: &str) -> fdo::Result<()> {
+        let repeat = match status {
+            "None" => RepeatMode::Off,
+            "Track" => RepeatMode::One,
+            "Playlist" => RepeatMode::All,
+            _ => return Err(fdo::Error::InvalidArgs(format!("Unknown loop status '{}'", status))),
+        };
+        self.record("LoopStatus", Value::from(loop_status(repeat)));
+        self.ctrl.set_repeat_mode(repeat);
+        Ok(())
+    }
+
+    /// We only play at normal speed
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn set_rate(&mut self, _rate: f64) {}
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn shuffle(&self) -> bool {
+        self.ctrl.snapshot().random
+    }
+
+    #[zbus(property)]
+    fn set_shuffle(&mut self, shuffle: bool) {
+        self.record("Shuffle", Value::from(shuffle));
+        self.ctrl.set_random(shuffle);
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<String, Value<'static>> {
+        let settings = self.ctrl.snapshot();
+        if settings.song_playing.is_empty() {
+            return metadata(None);
+        }
+        metadata(Some((Path::new(&settings.song_playing), settings.now_playing.as_ref())))
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        let settings = self.ctrl.snapshot();
+        volume(settings.volume, settings.muted)
+    }
+
+    #[zbus(property)]
+    fn set_volume(&mut self, volume: f64) {
+        let volume = (volume as f32).clamp(0.0, 1.0);
+        self.record("Volume", Value::from(self::volume(volume, self.ctrl.snapshot().muted)));
+        self.ctrl.set_volume(volume);
+    }
+
+    /// In microseconds. Not announced as it changes, listeners are expected to ask.
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn position(&self) -> i64 {
+        self.ctrl.snapshot().song_time.0 as i64 * MICROS_PER_SEC
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use zbus::blocking::{proxy, Proxy};
+    use zbus::proxy::CacheProperties;
+    use crate::controller::{InitialSettings, ResumeMode};
+    use super::*;
+
+    /// A dbus-daemon of our own, so the tests stay off the desktop's session bus
+    struct PrivateBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl PrivateBus {
+        fn start() -> Option<PrivateBus> {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take()?).read_line(&mut address).ok()?;
+            Some(PrivateBus {
+                daemon,
+                address: address.trim().to_string(),
+            })
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    #[test]
+    fn seek_target_keeps_sub_second_offsets() {
+        assert_eq!(seek_target((10, Some(60)), 500_000), SeekTarget::Position(10_500_000));
+        assert_eq!(seek_target((10, Some(60)), -250_000), SeekTarget::Position(9_750_000));
+    }
+
+    #[test]
+    fn seek_target_clamps_to_start() {
+        assert_eq!(seek_target((3, Some(60)), -10 * MICROS_PER_SEC), SeekTarget::Position(0));
+        assert_eq!(seek_target((3, None), i64::MIN), SeekTarget::Position(0));
+    }
+
+    #[test]
+    fn seek_target_past_end_is_next() {
+        assert_eq!(seek_target((55, Some(60)), 5 * MICROS_PER_SEC), SeekTarget::Next);
+        assert_eq!(seek_target((55, Some(60)), 30 * MICROS_PER_SEC), SeekTarget::Next);
+        assert_eq!(seek_target((55, Some(60)), 4_999_999), SeekTarget::Position(59_999_999));
+        // Without a length there is no end to go past
+        assert_eq!(seek_target((55, None), 30 * MICROS_PER_SEC), SeekTarget::Position(85 * MICROS_PER_SEC));
+    }
+
+    #[test]
+    fn serves_player_on_session_bus() {
+        let Some(bus) = PrivateBus::start() else {
+            eprintln!("Skipping, dbus-daemon isn't available");
+            return;
+        };
+        let scratch = std::env::temp_dir().join(format!("funoform_mpris_test_{}", std::process::id()));
+        std::fs::create_dir_all(&scratch).unwrap();
+        std::env::set_var("DBUS_SESSION_BUS_ADDRESS", &bus.address);
+        std::env::set_var("XDG_STATE_HOME", &scratch);
+        std::env::set_var("XDG_CACHE_HOME", &scratch);
+
+        let initial = InitialSettings {
+            random: Some(true),
+            resume: ResumeMode::Off,
+            ..InitialSettings::default()
+        };
+        let mut ctrl = Controller::init(scratch.to_string_lossy().to_string(), initial);
+        let _mpris = Mpris::init(ctrl.clone()).unwrap();
+
+        let client = connection::Builder::address(bus.address.as_str()).unwrap().build().unwrap();
+        // Ask every time, rather than going by the properties the proxy has seen change
+        let player: Proxy = proxy::Builder::new(&client)
+            .destination(BUS_NAME).unwrap()
+            .path(OBJECT_PATH).unwrap()
+            .interface(PLAYER_INTERFACE).unwrap()
+            .cache_properties(CacheProperties::No)
+            .build()
+            .unwrap();
+        assert_eq!(player.get_property::<String>("PlaybackStatus").unwrap(), "Stopped");
+        assert!(player.get_property::<bool>("Shuffle").unwrap());
+
+        player.set_property("Shuffle", false).unwrap();
+        assert!(!ctrl.snapshot().random);
+        player.set_property("LoopStatus", "Track").unwrap();
+        assert_eq!(ctrl.snapshot().repeat, RepeatMode::One);
+        assert!(player.set_property("LoopStatus", "Sometimes").is_err());
+
+        // With nothing playing, seeking does nothing rather than fail
+        player.call_method("Seek", &(500_000i64,)).unwrap();
+        assert_eq!(player.get_property::<i64>("Position").unwrap(), 0);
+        ctrl.set_repeat_mode(RepeatMode::All);
+        assert_eq!(player.get_property::<String>("LoopStatus").unwrap(), "Playlist");
+
+        let _ = std::fs::remove_dir_all(&scratch);
+    }
+}
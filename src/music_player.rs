@@ -1,38 +1,106 @@
+use std::fmt;
 use std::fs;
 use std::io::{self, BufReader};
-use std::os::unix::thread;
 use std::path::PathBuf;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::thread::{sleep, JoinHandle, Thread};
-use rodio::{Decoder, OutputStream, Sink, Source};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink, Source};
+use crate::metadata::metadata;
+use crate::player_event::PlaybackState;
+
+/// How often the playback thread wakes up to check for playback controls and song progress
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 struct CurSong {
+    _id: u64,
     _file_name: String,
-    _duration: u32,
-    // _sink: Sink,
-    _stream: OutputStream,
-    _stream_handle: rodio::OutputStreamHandle,
-    _thread: JoinHandle<()>,
+    // Length of the song in whole seconds, None if it couldn't be worked out
+    _duration: Option<u32>,
+    _sink: Arc<Sink>,
+    // Set when the song is being replaced so its thread exits without reporting completion
+    _cancelled: Arc<AtomicBool>,
+    _thread: Option<JoinHandle<()>>,
 }
 
 // Struct responsible for playing MP3 files
 pub struct MusicPlayer {
     end_of_song_notifier: std::sync::mpsc::Sender<PlaybackStatus>,
     _playback_controls_sender: std::sync::mpsc::Sender<PlaybackControls>,
-    _playback_controls_receiver: std::sync::mpsc::Receiver<PlaybackControls>,
-    _cur_song: Option<Arc<Mutex<CurSong>>>,
+    _playback_controls_receiver: Arc<Mutex<std::sync::mpsc::Receiver<PlaybackControls>>>,
+    _cur_song: Option<CurSong>,
+    // A single output stream is opened the first time we play and shared by every song after that
+    _stream: Option<(OutputStream, OutputStreamHandle)>,
+    _next_song_id: u64,
+    // Volume level, where 1.0 is the song's natural volume. Kept here so it carries over to the
+    // next song's sink.
+    _volume: f32,
+    _muted: bool,
+    // Only changed by our own methods, so it is always in step with what was last asked of us. A
+    // song that plays to the end stays Playing until the controller plays the next one or stops.
+    _state: PlaybackState,
 }
 
 pub enum PlaybackControls {
-    Pause(bool),
-    Play(bool),
-    Stop(bool),
+    // Jump to the specified position from the start of the song
+    Seek(Duration),
+}
+
+/// Why a song couldn't be played
+#[derive(Debug)]
+pub enum PlayerError {
+    /// The file isn't in a format we can decode, or is corrupt. Carries the file and the decoder's
+    /// description of what went wrong.
+    Decode(PathBuf, String),
+    /// There is no audio device to play on. Carries a description of why it couldn't be opened.
+    NoOutputDevice(String),
+    /// A file or directory couldn't be read
+    Io(io::Error),
+    /// The file's path isn't valid UTF-8, so it can't be shown to listeners
+    NonUtf8Path(PathBuf),
+    /// Asked to do something to the current song when there isn't one
+    NothingPlaying,
+}
+
+impl PlayerError {
+    /// Returns true if the error is down to the song itself, meaning other songs may still play
+    pub fn is_song_specific(&self) -> bool {
+        !matches!(self, PlayerError::NoOutputDevice(_) | PlayerError::NothingPlaying)
+    }
+}
+
+impl fmt::Display for PlayerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlayerError::Decode(path, reason) => write!(f, "Unable to decode {}: {}", path.display(), reason),
+            PlayerError::NoOutputDevice(reason) => write!(f, "No audio output device: {}", reason),
+            PlayerError::Io(e) => write!(f, "{}", e),
+            PlayerError::NonUtf8Path(path) => write!(f, "File name isn't valid UTF-8: {}", path.display()),
+            PlayerError::NothingPlaying => write!(f, "No song is playing"),
+        }
+    }
+}
+
+impl std::error::Error for PlayerError {}
+
+impl From<io::Error> for PlayerError {
+    fn from(e: io::Error) -> Self {
+        PlayerError::Io(e)
+    }
 }
 
+#[allow(clippy::enum_variant_names)]
 pub enum PlaybackStatus {
-    PlaybackComplete(bool),
-    // The pair is u32 elasped seconds, u32 total seconds
-    PlaybackPercentage(u32, u32)
+    // Carries the id of the song that finished, as returned by play_music_file
+    PlaybackComplete(u64),
+    // The pair is u32 elasped seconds, u32 total seconds. The total is None when the length of the
+    // song couldn't be worked out.
+    PlaybackPercentage(u32, Option<u32>),
+    // The decoder for the current song refused to seek. Carries a description of why, and the
+    // elapsed seconds the song is still at.
+    PlaybackSeekFailed(String, u32),
 }
 
 impl MusicPlayer {
@@ -42,8 +110,84 @@ impl MusicPlayer {
         MusicPlayer {
             end_of_song_notifier: song_over_notifier,
             _playback_controls_sender: playback_controls_sender,
-            _playback_controls_receiver: playback_controls_receiver,
+            _playback_controls_receiver: Arc::new(Mutex::new(playback_controls_receiver)),
             _cur_song: None,
+            _stream: None,
+            _next_song_id: 0,
+            _volume: 1.0,
+            _muted: false,
+            _state: PlaybackState::Stopped,
+        }
+    }
+
+    pub fn state(&self) -> PlaybackState {
+        self._state
+    }
+
+    /// Gets the length of the current song in whole seconds, None if there is no current song or
+    /// its length couldn't be worked out
+    pub fn cur_duration(&self) -> Option<u32> {
+        self._cur_song.as_ref().and_then(|cur_song| cur_song._duration)
+    }
+
+    /// Moves to the specified state. The state only changes when we are told to do something, so
+    /// whoever told us can check state() afterwards rather than waiting to be notified.
+    fn set_state(&mut self, state: PlaybackState) {
+        self._state = state;
+    }
+
+    /// Pauses the current song where it is. Does nothing unless a song is playing.
+    pub fn pause(&mut self) {
+        if self._state != PlaybackState::Playing {
+            return;
+        }
+        if let Some(cur_song) = &self._cur_song {
+            cur_song._sink.pause();
+        }
+        self.set_state(PlaybackState::Paused);
+    }
+
+    /// Carries on playing the current song from where it was paused. Does nothing unless a song
+    /// is paused.
+    pub fn resume(&mut self) {
+        if self._state != PlaybackState::Paused {
+            return;
+        }
+        if let Some(cur_song) = &self._cur_song {
+            cur_song._sink.play();
+        }
+        self.set_state(PlaybackState::Playing);
+    }
+
+    /// Stops the current song, if any. It will not report PlaybackComplete.
+    pub fn stop(&mut self) {
+        self.stop_cur_song();
+        self.set_state(PlaybackState::Stopped);
+    }
+
+    /// Sets the volume level, where 0.0 is silent and 1.0 is the song's natural volume
+    pub fn set_volume(&mut self, volume: f32) {
+        self._volume = volume;
+        self.apply_volume();
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self._muted = muted;
+        self.apply_volume();
+    }
+
+    /// Pushes our volume settings down to the sink of the current song, if any
+    fn apply_volume(&self) {
+        if let Some(cur_song) = &self._cur_song {
+            cur_song._sink.set_volume(self.effective_volume());
+        }
+    }
+
+    fn effective_volume(&self) -> f32 {
+        if self._muted {
+            0.0
+        } else {
+            self._volume
         }
     }
 
@@ -51,47 +195,168 @@ impl MusicPlayer {
         self._playback_controls_sender.clone()
     }
 
-    pub fn play_music_file(&mut self, file_path: &PathBuf) -> io::Result<()> {
+    /// Returns true if the specified id belongs to the song currently loaded in the player. Used to
+    /// ignore notifications from songs that were replaced before they could be processed.
+    pub fn is_cur_song(&self, song_id: u64) -> bool {
+        self._cur_song.as_ref().is_some_and(|cur_song| cur_song._id == song_id)
+    }
 
-        // TODO: Kill thread if one is already playing. Will require making _sink available to curSon
+    /// Stops the current song, if any, and waits for its playback thread to exit. The song will not
+    /// report PlaybackComplete.
+    fn stop_cur_song(&mut self) {
+        if let Some(mut cur_song) = self._cur_song.take() {
+            cur_song._cancelled.store(true, Ordering::SeqCst);
+            cur_song._sink.stop();
+            if let Some(thread) = cur_song._thread.take() {
+                if thread.join().is_err() {
+                    eprintln!("Playback thread for {} panicked", cur_song._file_name);
+                }
+            }
+        }
+    }
 
+    /// Starts playing the specified file, replacing whatever song was already playing. If the file
+    /// can't be played, the player is left stopped.
+    /// Returns an id for the song which is included in its PlaybackComplete notification.
+    /// # Parameters
+    /// start_pos = How far into the song to start playing
+    /// start_paused = If true, the song is left paused at start_pos until resume is called
+    /// known_duration = The length of the song in whole seconds, if it is already known. Saves
+    ///                  reading the whole file for the length of songs that don't record it.
+    pub fn play_music_file(&mut self, file_path: &PathBuf, start_pos: Duration, start_paused: bool,
+                           known_duration: Option<u32>) -> Result<u64, PlayerError> {
+        self.stop_cur_song();
+        match self.start_music_file(file_path, start_pos, start_paused, known_duration) {
+            Ok(song_id) => {
+                self.set_state(if start_paused { PlaybackState::Paused } else { PlaybackState::Playing });
+                Ok(song_id)
+            }
+            Err(e) => {
+                self.set_state(PlaybackState::Stopped);
+                Err(e)
+            }
+        }
+    }
 
-        println!("Playing {}", file_path.to_str().unwrap().to_string());
-        let cur_playing_file_name = Some(file_path.to_str().unwrap().to_string());
+    /// Loads the specified file into a new sink and starts the thread that reports on it. See
+    /// play_music_file.
+    fn start_music_file(&mut self, file_path: &PathBuf, start_pos: Duration, start_paused: bool,
+                        known_duration: Option<u32>) -> Result<u64, PlayerError> {
+        println!("Playing {}", file_path.display());
+        let cur_playing_file_name = file_path.to_str()
+            .ok_or_else(|| PlayerError::NonUtf8Path(file_path.clone()))?
+            .to_string();
 
         // Open the MP3 file and decode it for playback
-        let file = fs::File::open(file_path)?;
-        let source = Decoder::new(BufReader::new(file)).expect("Failed to decode music file");
+        let file = fs::File::open(file_path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Unable to open {}: {}", file_path.display(), e)))?;
+        let source = Decoder::new(BufReader::new(file))
+            .map_err(|e| PlayerError::Decode(file_path.clone(), e.to_string()))?;
 
-        let song_duration: u32 = source.total_duration().unwrap().as_secs().try_into().unwrap();
+        // The decoder doesn't know the length of many VBR mp3s and oggs, so go to the file for it
+        // unless we were told it
+        let as_secs = |duration: Duration| duration.as_secs().try_into().unwrap_or(u32::MAX);
+        let song_duration: Option<u32> = source.total_duration().map(as_secs)
+            .or(known_duration)
+            .or_else(|| metadata::read_duration(file_path).map(as_secs));
 
-        let (stream, stream_handle) = OutputStream::try_default().expect("Failed to open audio output stream");
-        let sink = Sink::try_new(&stream_handle).expect("Failed to create sink");
+        // Open the output stream the first time we play. If there's no device, try again next time
+        // in case one has been plugged in since.
+        let stream_handle = match &self._stream {
+            Some((_, stream_handle)) => stream_handle,
+            None => {
+                let stream = OutputStream::try_default().map_err(|e| PlayerError::NoOutputDevice(e.to_string()))?;
+                &self._stream.insert(stream).1
+            }
+        };
+        let sink = Arc::new(Sink::try_new(stream_handle).map_err(|e| PlayerError::NoOutputDevice(e.to_string()))?);
+        sink.set_volume(self.effective_volume());
+        // Hold off playing until we are at the starting position
+        sink.pause();
         sink.append(source);
-        sink.play();
+        if !start_pos.is_zero() {
+            if let Err(e) = sink.try_seek(start_pos) {
+                eprintln!("Unable to start {} at {}s: {}", file_path.display(), start_pos.as_secs(), e);
+            }
+        }
+        if !start_paused {
+            sink.play();
+        }
+
+        // Drop any controls that were sent while no song was playing so they don't get applied
+        // to the song we just started
+        let controls = Arc::clone(&self._playback_controls_receiver);
+        while controls.lock().unwrap().try_recv().is_ok() {}
+
+        let song_id = self._next_song_id;
+        self._next_song_id += 1;
+        let cancelled = Arc::new(AtomicBool::new(false));
 
         let eosn = self.end_of_song_notifier.clone();
+        let thread_sink = Arc::clone(&sink);
+        let thread_cancelled = Arc::clone(&cancelled);
         let thread = std::thread::spawn(move || {
-            while !sink.empty() {
-                let cur_pos: u32 = sink.get_pos().as_secs().try_into().unwrap();
-                match eosn.send(PlaybackStatus::PlaybackPercentage(cur_pos, song_duration)) {
-                    Ok(_) => (),
-                    Err(e) => eprintln!("Failed to send playback percentage: {}", e),
+            let sink = thread_sink;
+            let mut last_reported_pos: Option<u32> = None;
+            while !sink.empty() && !thread_cancelled.load(Ordering::SeqCst) {
+                // Block for a short while waiting on a seek request. Timing out just means nobody
+                // asked us to do anything, so we go report our progress.
+                let control = controls.lock().unwrap().recv_timeout(POLL_INTERVAL);
+                let status = match control {
+                    Ok(PlaybackControls::Seek(pos)) => match sink.try_seek(pos) {
+                        Ok(_) => {
+                            // Report the new position right away rather than waiting on the
+                            // next once per second update
+                            let cur_pos: u32 = sink.get_pos().as_secs().try_into().unwrap_or(u32::MAX);
+                            last_reported_pos = Some(cur_pos);
+                            Some(PlaybackStatus::PlaybackPercentage(cur_pos, song_duration))
+                        }
+                        Err(e) => {
+                            let cur_pos: u32 = sink.get_pos().as_secs().try_into().unwrap_or(u32::MAX);
+                            last_reported_pos = Some(cur_pos);
+                            Some(PlaybackStatus::PlaybackSeekFailed(e.to_string(), cur_pos))
+                        }
+                    },
+                    Err(RecvTimeoutError::Timeout) => {
+                        // Only report progress once per second while we are actually playing
+                        let cur_pos: u32 = sink.get_pos().as_secs().try_into().unwrap_or(u32::MAX);
+                        if sink.is_paused() || last_reported_pos == Some(cur_pos) {
+                            None
+                        } else {
+                            last_reported_pos = Some(cur_pos);
+                            Some(PlaybackStatus::PlaybackPercentage(cur_pos, song_duration))
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => return,
+                };
+
+                if let Some(status) = status {
+                    if let Err(e) = eosn.send(status) {
+                        eprintln!("Failed to send playback status: {}", e);
+                    }
                 }
-                // TODO: read _playback_controls_receiver to see if the song is supposed to play/pause/stop
-                sleep(std::time::Duration::from_millis(1000));
+            }
+
+            // A replaced song isn't complete, the player has already moved on to another one
+            if thread_cancelled.load(Ordering::SeqCst) {
+                return;
+            }
+
+            // The sink ran out of audio on its own, so the song played all the way through
+            if let Err(e) = eosn.send(PlaybackStatus::PlaybackComplete(song_id)) {
+                eprintln!("Failed to send playback complete: {}", e);
             }
         });
 
-        self._cur_song = Some(Arc::new(Mutex::new(CurSong {
-            _file_name: cur_playing_file_name.unwrap(),
+        self._cur_song = Some(CurSong {
+            _id: song_id,
+            _file_name: cur_playing_file_name,
             _duration: song_duration,
-            // _sink: sink,
-            _stream: stream,
-            _stream_handle: stream_handle,
-            _thread: thread,
-        })));
+            _sink: sink,
+            _cancelled: cancelled,
+            _thread: Some(thread),
+        });
 
-        Ok(())
+        Ok(song_id)
     }
-}
\ No newline at end of file
+}
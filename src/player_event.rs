@@ -0,0 +1,65 @@
+use std::path::PathBuf;
+use serde::Serialize;
+use crate::event_bus::BusEvent;
+use crate::metadata::metadata::TrackMetadata;
+use crate::settings_changed::RepeatMode;
+
+/// Whether a song is playing, paused part way through, or there's no song at all
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaybackState {
+    Playing,
+    Paused,
+    Stopped,
+}
+
+/// The settings the user toggles, sent together whenever any of them changes
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Modes {
+    pub random: bool,
+    pub repeat: RepeatMode,
+    pub recursive: bool,
+    // Volume level from 0.0 (silent) to 1.0 (the song's natural volume)
+    pub volume: f32,
+    pub muted: bool,
+}
+
+/// Something that happened in the controller. Listeners that need the full state rather than
+/// what just changed can ask for Controller::snapshot. Serializes as {"event": "track_started",
+/// "data": ...}, with the event's name in snake case.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum PlayerEvent {
+    /// A song was loaded, either playing or paused. Carries its path and its tags, which are None
+    /// if they couldn't be read.
+    TrackStarted(PathBuf, Option<TrackMetadata>),
+    /// A song stopped being the current song, because it played to the end, another song
+    /// replaced it, or playback was stopped
+    TrackEnded(PathBuf),
+    /// The pair is u32 elasped seconds, u32 total seconds. The total is None when the length of the
+    /// song isn't known.
+    PositionChanged(u32, Option<u32>),
+    StateChanged(PlaybackState),
+    ModeChanged(Modes),
+    /// The songs in the queue changed. Carries the new length and queue_version.
+    QueueChanged(usize, u64),
+    /// The directory being browsed or the one the queue came from changed. Carries the browsing
+    /// directory, then the playing directory, which is a playlist file when one is playing.
+    DirChanged(String, String),
+    /// Something went wrong, like a song that couldn't be played. Carries a description of it.
+    Error(String),
+    /// The listener fell too far behind and events were dropped. Anything it tracks should be
+    /// fetched again from Controller::snapshot.
+    Resync,
+}
+
+impl BusEvent for PlayerEvent {
+    /// Progress updates come once a second, so a listener that misses some just shows the next
+    fn is_transient(&self) -> bool {
+        matches!(self, PlayerEvent::PositionChanged(..))
+    }
+
+    fn resync() -> Self {
+        PlayerEvent::Resync
+    }
+}
@@ -0,0 +1,80 @@
+/// Remembers bits of player state between runs of the application
+#[allow(clippy::module_inception)]
+pub mod saved_state {
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+    use serde::{Deserialize, Serialize};
+    use crate::settings_changed::RepeatMode;
+
+    pub const APP_DIR_NAME: &str = "funoform_mp3_dir_player";
+    const SESSION_FILE_NAME: &str = "session.json";
+
+    /// Everything needed to pick up playback where the last run left off
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Session {
+        pub browsing_dir: String,
+        // The directory or playlist the queue came from
+        pub playing_dir: String,
+        // The songs in the queue, in sequential order
+        pub queue: Vec<PathBuf>,
+        // True if the queue includes files from subdirectories of the playing directory
+        pub queued_recursive: bool,
+        // Index into queue of the song that was playing, None if nothing was
+        pub cur_index: Option<usize>,
+        // How far into the current song we were, in whole seconds
+        pub position_secs: u32,
+        // The random order songs were playing in, and how far through it we were
+        pub shuffle_order: Vec<usize>,
+        pub shuffle_pos: usize,
+        // Volume level from 0.0 (silent) to 1.0 (the song's natural volume)
+        pub volume: f32,
+        pub random: bool,
+        pub repeat: RepeatMode,
+        pub recursive: bool,
+    }
+
+    /// Gets the directory our state files live in. Follows the XDG base directory spec, so this is
+    /// $XDG_STATE_HOME/funoform_mp3_dir_player, or ~/.local/state/funoform_mp3_dir_player if
+    /// $XDG_STATE_HOME isn't set. Returns None if neither variable is available.
+    pub fn state_dir() -> Option<PathBuf> {
+        let base_dir = match std::env::var_os("XDG_STATE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".local").join("state"),
+        };
+        Some(base_dir.join(APP_DIR_NAME))
+    }
+
+    /// Reads the session saved by the last run, if there was one and it can be read
+    pub fn load_session() -> Option<Session> {
+        let contents = fs::read_to_string(state_dir()?.join(SESSION_FILE_NAME)).ok()?;
+        match serde_json::from_str(&contents) {
+            Ok(session) => Some(session),
+            Err(e) => {
+                eprintln!("Ignoring unreadable saved session: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Saves the session so the next run can resume it
+    pub fn save_session(session: &Session) {
+        let Some(dir) = state_dir() else {
+            return;
+        };
+
+        // Write to a temporary file first so a crash part way through doesn't lose the session
+        let session_file = dir.join(SESSION_FILE_NAME);
+        let tmp_file = session_file.with_extension("json.tmp");
+        let res = serde_json::to_string(session)
+            .map_err(io::Error::from)
+            .and_then(|contents| {
+                fs::create_dir_all(&dir)?;
+                fs::write(&tmp_file, contents)?;
+                fs::rename(&tmp_file, &session_file)
+            });
+        if let Err(e) = res {
+            eprintln!("Failed to save session to {}: {}", session_file.display(), e);
+        }
+    }
+}
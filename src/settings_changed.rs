@@ -1,13 +1,53 @@
-#[derive(Debug)]
+use serde::{Deserialize, Serialize};
+
+/// What to do once a song finishes playing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RepeatMode {
+    /// Stop once every song in the queue has played
+    Off,
+    /// Start the queue over once every song has played
+    All,
+    /// Keep playing the current song over and over
+    One,
+}
+
+impl RepeatMode {
+    /// Gets the mode after this one, for UIs that step through the modes with a single button
+    pub fn next(self) -> RepeatMode {
+        match self {
+            RepeatMode::Off => RepeatMode::All,
+            RepeatMode::All => RepeatMode::One,
+            RepeatMode::One => RepeatMode::Off,
+        }
+    }
+}
+
+use crate::metadata::metadata::TrackMetadata;
+use crate::player_event::{Modes, PlaybackState};
+
+#[derive(Debug, Serialize)]
 pub struct SettingsChanged {
     pub recursive: bool,
-    pub repeat: bool,
+    pub repeat: RepeatMode,
     pub random: bool,
     pub playing_dir: String,
     pub browsing_dir: String,
     pub song_playing: String,
-    // The pair is u32 elasped seconds, u32 total seconds
-    pub song_time: (u32, u32)
+    // The tags of the song playing, None if nothing is playing or its tags couldn't be read
+    pub now_playing: Option<TrackMetadata>,
+    // How many songs are queued up from the playing directory
+    pub queue_len: usize,
+    // Goes up by one every time the queue changes, so listeners know to fetch it again
+    pub queue_version: u64,
+    // The pair is u32 elasped seconds, u32 total seconds. The total is None when the length of the
+    // song isn't known.
+    pub song_time: (u32, Option<u32>),
+    // Whether the current song is playing or paused, Stopped if there isn't one
+    pub state: PlaybackState,
+    // Volume level from 0.0 (silent) to 1.0 (the song's natural volume)
+    pub volume: f32,
+    pub muted: bool,
 }
 
 impl Clone for SettingsChanged {
@@ -19,7 +59,26 @@ impl Clone for SettingsChanged {
             playing_dir: self.playing_dir.clone(),
             browsing_dir: self.browsing_dir.clone(),
             song_playing: self.song_playing.clone(),
-            song_time: self.song_time
+            now_playing: self.now_playing.clone(),
+            queue_len: self.queue_len,
+            queue_version: self.queue_version,
+            song_time: self.song_time,
+            state: self.state,
+            volume: self.volume,
+            muted: self.muted,
         }
     }
-}
\ No newline at end of file
+}
+
+impl SettingsChanged {
+    /// Gets the settings the user toggles, as sent in ModeChanged
+    pub fn modes(&self) -> Modes {
+        Modes {
+            random: self.random,
+            repeat: self.repeat,
+            recursive: self.recursive,
+            volume: self.volume,
+            muted: self.muted,
+        }
+    }
+}